@@ -1,6 +1,11 @@
+use std::{convert::TryFrom, fmt, rc::Rc};
+use crate::compiler::token::Span;
+
+pub mod verifier;
+
 #[repr(i32)]
-#[allow(dead_code)]
-#[derive(Debug)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     PUSH,
     GLOAD,
@@ -26,13 +31,314 @@ pub enum OpCode {
     // Jumping
     JMP,
     JMP0,
-    JMP1
+    JMP1,
+    // Literals that don't fit in an i32 operand
+    NIL,
+    TRUE,
+    FALSE,
+    CONST,
+    // Strings
+    LEN,
+    INDEX,
+    SLICE,
+    // Memory
+    GC,
+    // Conversions
+    STR,
+    // Arithmetic and logic
+    MOD,
+    POW,
+    NEG,
+    NOT,
+    // Bitwise
+    BAND,
+    BOR,
+    BXOR,
+    BNOT,
+    SHL,
+    SHR,
+    // Stack
+    DUP,
+    // Call frames
+    ENTER,
+}
+
+impl OpCode {
+    // How many operands follow the opcode in the program
+    pub fn operand_count(&self) -> usize {
+        match self {
+            OpCode::CALL => 2,
+            OpCode::PUSH
+            | OpCode::GLOAD
+            | OpCode::GSTORE
+            | OpCode::LLOAD
+            | OpCode::LSTORE
+            | OpCode::JMP
+            | OpCode::JMP0
+            | OpCode::JMP1
+            | OpCode::CONST
+            | OpCode::ENTER => 1,
+            _ => 0,
+        }
+    }
+}
+
+// Every opcode, in the order of their values
+const OPCODES: [OpCode; 44] = [
+    OpCode::PUSH,
+    OpCode::GLOAD,
+    OpCode::GSTORE,
+    OpCode::LLOAD,
+    OpCode::LSTORE,
+    OpCode::CALL,
+    OpCode::RET,
+    OpCode::ADD,
+    OpCode::SUB,
+    OpCode::MUL,
+    OpCode::DIV,
+    OpCode::PRINT,
+    OpCode::HALT,
+    OpCode::POP,
+    OpCode::EQ,
+    OpCode::NE,
+    OpCode::GT,
+    OpCode::LT,
+    OpCode::GE,
+    OpCode::LE,
+    OpCode::JMP,
+    OpCode::JMP0,
+    OpCode::JMP1,
+    OpCode::NIL,
+    OpCode::TRUE,
+    OpCode::FALSE,
+    OpCode::CONST,
+    OpCode::LEN,
+    OpCode::INDEX,
+    OpCode::SLICE,
+    OpCode::GC,
+    OpCode::STR,
+    OpCode::MOD,
+    OpCode::POW,
+    OpCode::NEG,
+    OpCode::NOT,
+    OpCode::BAND,
+    OpCode::BOR,
+    OpCode::BXOR,
+    OpCode::BNOT,
+    OpCode::SHL,
+    OpCode::SHR,
+    OpCode::DUP,
+    OpCode::ENTER,
+];
+
+// A value in the program that is not an opcode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidOpcode(pub i32);
+
+impl fmt::Display for InvalidOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid opcode {}", self.0)
+    }
 }
 
-impl From<i32> for OpCode {
-    fn from(n: i32) -> Self {
-        unsafe { std::mem::transmute(n) }
+impl TryFrom<i32> for OpCode {
+    type Error = InvalidOpcode;
+
+    fn try_from(n: i32) -> Result<Self, Self::Error> {
+        usize::try_from(n)
+            .ok()
+            .and_then(|index| OPCODES.get(index))
+            .copied()
+            .ok_or(InvalidOpcode(n))
     }
 }
 
 pub const FUNC_PARAM_OFFSET: i32 = 3;
+
+// The number of global variables a program can have
+pub const GLOBALS_SIZE: usize = 1024;
+
+// An entry of the constant pool of a program. Numbers and strings are
+// loaded on the stack by CONST, the functions describe the code that
+// CALL jumps to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Int(i64),
+    Float(f64),
+    String(Rc<str>),
+    Function { name: Rc<str>, addr: usize, arity: usize },
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constant::Int(n) => write!(f, "{}", n),
+            Constant::Float(n) => write!(f, "{:?}", n),
+            Constant::String(s) => write!(f, "{:?}", s),
+            Constant::Function { name, arity, .. } => write!(f, "fn {}/{}", name, arity),
+        }
+    }
+}
+
+// A compiled program, ready to be loaded into the VirtualMachine
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub code: Vec<i32>,
+    pub entrypoint: usize,
+    pub constants: Vec<Constant>,
+    // Where the code comes from in the source: (address, span) pairs sorted
+    // by address, each span covers the code up to the next address
+    pub spans: Vec<(usize, Span)>,
+}
+
+impl Program {
+    // The source of the instruction at `ip`, to report runtime errors
+    pub fn span_at(&self, ip: usize) -> Option<Span> {
+        span_at(&self.spans, ip)
+    }
+}
+
+pub fn span_at(spans: &[(usize, Span)], ip: usize) -> Option<Span> {
+    let index = spans.partition_point(|(addr, _)| *addr <= ip);
+    index.checked_sub(1).map(|index| spans[index].1)
+}
+
+// Render a program as one instruction per line, prefixed with its address,
+// with the constants and the functions they refer to after a `;`:
+//
+//   0000  CONST 0  ; "hello"
+//   0002  GSTORE 0
+//   0004  CALL 9 1  ; fn greet/1
+//
+pub fn disassemble(program: &Program) -> String {
+    let mut output = String::new();
+    let mut addr = 0;
+    while addr < program.code.len() {
+        let marker = if addr == program.entrypoint { "> " } else { "  " };
+        let opcode = match OpCode::try_from(program.code[addr]) {
+            Ok(opcode) => opcode,
+            Err(InvalidOpcode(value)) => {
+                output.push_str(&format!("{}{:04}  ??? {}\n", marker, addr, value));
+                addr += 1;
+                continue;
+            }
+        };
+        let mut line = format!("{}{:04}  {:?}", marker, addr, opcode);
+        let operands = program.code.iter().skip(addr + 1).take(opcode.operand_count());
+        for operand in operands.clone() {
+            line.push_str(&format!(" {}", operand));
+        }
+        let operand = operands.copied().next().unwrap_or(-1);
+        let comment = match opcode {
+            OpCode::CONST => usize::try_from(operand).ok().and_then(|index| program.constants.get(index)),
+            OpCode::CALL => program.constants.iter().find(|constant| {
+                matches!(constant, Constant::Function { addr, .. } if *addr as i32 == operand)
+            }),
+            _ => None,
+        };
+        if let Some(constant) = comment {
+            line.push_str(&format!("  ; {}", constant));
+        }
+        output.push_str(&line);
+        output.push('\n');
+        addr += 1 + opcode.operand_count();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use super::{disassemble, Constant, InvalidOpcode, OpCode, Program, OPCODES};
+    use crate::compiler::token::Span;
+
+    #[test]
+    fn test_disassemble() {
+        let program = Program {
+            code: vec![
+                OpCode::LLOAD as i32, -4,
+                OpCode::RET as i32,
+                OpCode::PUSH as i32, 8,
+                OpCode::CALL as i32, 0, 1,
+                OpCode::PRINT as i32,
+                OpCode::HALT as i32,
+            ],
+            entrypoint: 3,
+            constants: vec![],
+            spans: vec![],
+        };
+        assert_eq!(disassemble(&program), [
+            "  0000  LLOAD -4",
+            "  0002  RET",
+            "> 0003  PUSH 8",
+            "  0005  CALL 0 1",
+            "  0008  PRINT",
+            "  0009  HALT",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_disassemble_constants() {
+        let program = Program {
+            code: vec![
+                OpCode::LLOAD as i32, -4,
+                OpCode::RET as i32,
+                OpCode::CONST as i32, 1,
+                OpCode::CALL as i32, 0, 1,
+                OpCode::HALT as i32,
+            ],
+            entrypoint: 3,
+            constants: vec![
+                Constant::Function { name: "id".into(), addr: 0, arity: 1 },
+                Constant::String("tên".into()),
+            ],
+            spans: vec![],
+        };
+        assert_eq!(disassemble(&program), [
+            "  0000  LLOAD -4",
+            "  0002  RET",
+            "> 0003  CONST 1  ; \"tên\"",
+            "  0005  CALL 0 1  ; fn id/1",
+            "  0008  HALT",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_span_at() {
+        let first = Span { line: 1, column: 1, start: 0, len: 5 };
+        let second = Span { line: 2, column: 1, start: 6, len: 3 };
+        let program = Program {
+            code: vec![],
+            entrypoint: 0,
+            constants: vec![],
+            spans: vec![(2, first), (6, second)],
+        };
+        assert_eq!(program.span_at(0), None);
+        assert_eq!(program.span_at(2), Some(first));
+        assert_eq!(program.span_at(5), Some(first));
+        assert_eq!(program.span_at(9), Some(second));
+    }
+
+    #[test]
+    fn test_opcode_decoding() {
+        for (value, opcode) in OPCODES.iter().enumerate() {
+            assert_eq!(*opcode as i32, value as i32);
+            assert_eq!(OpCode::try_from(value as i32), Ok(*opcode));
+        }
+        assert_eq!(OpCode::try_from(OPCODES.len() as i32), Err(InvalidOpcode(OPCODES.len() as i32)));
+        assert_eq!(OpCode::try_from(-1), Err(InvalidOpcode(-1)));
+    }
+
+    #[test]
+    fn test_disassemble_invalid_opcode() {
+        let program = Program {
+            code: vec![OpCode::PUSH as i32, 1, 99, OpCode::HALT as i32],
+            entrypoint: 0,
+            constants: vec![],
+            spans: vec![],
+        };
+        assert_eq!(disassemble(&program), "> 0000  PUSH 1\n  0002  ??? 99\n  0003  HALT\n");
+    }
+}
@@ -0,0 +1,426 @@
+use std::{collections::HashMap, convert::TryFrom, fmt};
+use super::{Constant, InvalidOpcode, OpCode, Program, FUNC_PARAM_OFFSET, GLOBALS_SIZE};
+use crate::diagnostics::Diagnostic;
+
+// The verifier checks a whole program before it runs, so the VirtualMachine
+// doesn't have to check every instruction again:
+// - every opcode is valid and has all of its operands
+// - jumps and calls land on an instruction inside the program
+// - the entrypoint is an instruction
+// - the execution never runs past the last instruction
+// - the global and local addresses are in range
+// - CONST loads a number or a string from the constant pool
+// - an instruction always sees the same stack depth, no matter which path
+//   reaches it, and never pops more than its call frame has
+//
+// The depth is counted from the frame pointer: the top-level code starts
+// with an empty stack, and so does every function, the arguments and the
+// three values pushed by CALL are below its frame.
+//
+// Whether a function recurses too deep, or divides by zero, is only known
+// at runtime, the VirtualMachine still checks for those.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    InvalidOpcode { addr: usize, value: i32 },
+    MissingOperand { addr: usize, opcode: OpCode },
+    InvalidEntrypoint { entrypoint: usize },
+    InvalidJump { addr: usize, opcode: OpCode, target: i32 },
+    InvalidArgCount { addr: usize, argc: i32 },
+    InvalidFrameSize { addr: usize, size: i32 },
+    InvalidGlobal { addr: usize, opcode: OpCode, slot: i32 },
+    InvalidLocal { addr: usize, opcode: OpCode, offset: i32 },
+    InvalidConstant { addr: usize, index: i32 },
+    FallsOffEnd { addr: usize, opcode: OpCode },
+    StackUnderflow { addr: usize, opcode: OpCode },
+    InconsistentStack { addr: usize, expected: usize, found: usize },
+    // The same code is reached from different functions, or from a function
+    // and the top-level code
+    InconsistentFrame { addr: usize },
+    ReturnOutsideFunction { addr: usize },
+}
+
+impl VerifyError {
+    // The address of the instruction that failed, for the source map
+    pub fn addr(&self) -> usize {
+        match self {
+            VerifyError::InvalidOpcode { addr, .. }
+            | VerifyError::MissingOperand { addr, .. }
+            | VerifyError::InvalidJump { addr, .. }
+            | VerifyError::InvalidArgCount { addr, .. }
+            | VerifyError::InvalidFrameSize { addr, .. }
+            | VerifyError::InvalidGlobal { addr, .. }
+            | VerifyError::InvalidLocal { addr, .. }
+            | VerifyError::InvalidConstant { addr, .. }
+            | VerifyError::FallsOffEnd { addr, .. }
+            | VerifyError::StackUnderflow { addr, .. }
+            | VerifyError::InconsistentStack { addr, .. }
+            | VerifyError::InconsistentFrame { addr }
+            | VerifyError::ReturnOutsideFunction { addr } => *addr,
+            VerifyError::InvalidEntrypoint { entrypoint } => *entrypoint,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            VerifyError::InvalidOpcode { value, .. } => InvalidOpcode(*value).to_string(),
+            VerifyError::MissingOperand { opcode, .. } => format!("{:?} is missing an operand", opcode),
+            VerifyError::InvalidEntrypoint { entrypoint } => {
+                format!("entrypoint {} is not an instruction", entrypoint)
+            }
+            VerifyError::InvalidJump { opcode, target, .. } => {
+                format!("{:?} to {}, which is not an instruction", opcode, target)
+            }
+            VerifyError::InvalidArgCount { argc, .. } => format!("invalid argument count {}", argc),
+            VerifyError::InvalidFrameSize { size, .. } => format!("invalid frame size {}", size),
+            VerifyError::InvalidGlobal { opcode, slot, .. } => {
+                format!("{:?} of invalid global address {}", opcode, slot)
+            }
+            VerifyError::InvalidLocal { opcode, offset, .. } => {
+                format!("{:?} of invalid local offset {}", opcode, offset)
+            }
+            VerifyError::InvalidConstant { index, .. } => format!("CONST of invalid constant {}", index),
+            VerifyError::FallsOffEnd { opcode, .. } => {
+                format!("execution runs past the end of the program after {:?}", opcode)
+            }
+            VerifyError::StackUnderflow { opcode, .. } => format!("stack underflow in {:?}", opcode),
+            VerifyError::InconsistentStack { expected, found, .. } => {
+                format!("stack depth is {} on one path and {} on another", expected, found)
+            }
+            VerifyError::InconsistentFrame { .. } => "code is shared between call frames".to_string(),
+            VerifyError::ReturnOutsideFunction { .. } => "RET outside of a function".to_string(),
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let code = match self {
+            VerifyError::InvalidOpcode { .. } => "E0401",
+            VerifyError::MissingOperand { .. } => "E0402",
+            VerifyError::InvalidEntrypoint { .. } => "E0403",
+            VerifyError::InvalidJump { .. } => "E0404",
+            VerifyError::InvalidArgCount { .. } => "E0405",
+            VerifyError::InvalidGlobal { .. } => "E0406",
+            VerifyError::InvalidLocal { .. } => "E0407",
+            VerifyError::FallsOffEnd { .. } => "E0408",
+            VerifyError::StackUnderflow { .. } => "E0409",
+            VerifyError::InconsistentStack { .. } => "E0410",
+            VerifyError::InconsistentFrame { .. } => "E0411",
+            VerifyError::ReturnOutsideFunction { .. } => "E0412",
+            VerifyError::InvalidConstant { .. } => "E0413",
+            VerifyError::InvalidFrameSize { .. } => "E0414",
+        };
+        Diagnostic::error(code, self.message())
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}: {}", self.addr(), self.message())
+    }
+}
+
+// What the verifier knows about the stack before an instruction runs
+#[derive(Clone, Copy, PartialEq)]
+struct Frame {
+    depth: usize,
+    // The number of arguments of the current function, None in the
+    // top-level code
+    argc: Option<usize>,
+}
+
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    let instructions = decode(&program.code, &program.constants)?;
+    if !instructions.contains_key(&program.entrypoint) {
+        return Err(VerifyError::InvalidEntrypoint { entrypoint: program.entrypoint });
+    }
+    let mut frames: HashMap<usize, Frame> = HashMap::new();
+    let mut pending = vec![(program.entrypoint, Frame { depth: 0, argc: None })];
+    while let Some((addr, frame)) = pending.pop() {
+        match frames.get(&addr) {
+            Some(seen) if *seen == frame => continue,
+            Some(seen) if seen.argc != frame.argc => {
+                return Err(VerifyError::InconsistentFrame { addr });
+            }
+            Some(seen) => {
+                return Err(VerifyError::InconsistentStack {
+                    addr,
+                    expected: seen.depth,
+                    found: frame.depth,
+                });
+            }
+            None => {}
+        }
+        frames.insert(addr, frame);
+
+        let (opcode, operands) = instructions[&addr];
+        let (pops, pushes) = stack_effect(opcode, operands);
+        if frame.depth < pops {
+            return Err(VerifyError::StackUnderflow { addr, opcode });
+        }
+        let depth = frame.depth - pops + pushes;
+        let next = addr + 1 + operands.len();
+        let mut fall_through = true;
+        match opcode {
+            OpCode::LLOAD | OpCode::LSTORE => {
+                let offset = operands[0];
+                let local = usize::try_from(offset).is_ok_and(|offset| offset < frame.depth - pops);
+                let param = frame.argc.is_some_and(|argc| {
+                    offset < -FUNC_PARAM_OFFSET && offset >= -(FUNC_PARAM_OFFSET + argc as i32)
+                });
+                if !local && !param {
+                    return Err(VerifyError::InvalidLocal { addr, opcode, offset });
+                }
+            }
+            OpCode::CALL => {
+                let argc = pops;
+                pending.push((operands[0] as usize, Frame { depth: 0, argc: Some(argc) }));
+            }
+            OpCode::RET => {
+                if frame.argc.is_none() {
+                    return Err(VerifyError::ReturnOutsideFunction { addr });
+                }
+                fall_through = false;
+            }
+            OpCode::HALT => fall_through = false,
+            OpCode::JMP => {
+                pending.push((operands[0] as usize, Frame { depth, ..frame }));
+                fall_through = false;
+            }
+            OpCode::JMP0 | OpCode::JMP1 => {
+                pending.push((operands[0] as usize, Frame { depth, ..frame }));
+            }
+            _ => {}
+        }
+        if fall_through {
+            if !instructions.contains_key(&next) {
+                return Err(VerifyError::FallsOffEnd { addr, opcode });
+            }
+            pending.push((next, Frame { depth, ..frame }));
+        }
+    }
+    Ok(())
+}
+
+// Split the code into instructions, and check everything that doesn't
+// depend on the path taken to reach them
+fn decode<'a>(code: &'a [i32], constants: &[Constant]) -> Result<HashMap<usize, (OpCode, &'a [i32])>, VerifyError> {
+    let mut instructions = HashMap::new();
+    let mut addr = 0;
+    while addr < code.len() {
+        let opcode = OpCode::try_from(code[addr])
+            .map_err(|InvalidOpcode(value)| VerifyError::InvalidOpcode { addr, value })?;
+        let operands = code
+            .get(addr + 1..addr + 1 + opcode.operand_count())
+            .ok_or(VerifyError::MissingOperand { addr, opcode })?;
+        match opcode {
+            OpCode::GLOAD | OpCode::GSTORE => {
+                let slot = operands[0];
+                if usize::try_from(slot).map_or(true, |slot| slot >= GLOBALS_SIZE) {
+                    return Err(VerifyError::InvalidGlobal { addr, opcode, slot });
+                }
+            }
+            OpCode::CONST => {
+                let index = operands[0];
+                let constant = usize::try_from(index).ok().and_then(|index| constants.get(index));
+                if matches!(constant, None | Some(Constant::Function { .. })) {
+                    return Err(VerifyError::InvalidConstant { addr, index });
+                }
+            }
+            OpCode::CALL if operands[1] < 0 => {
+                return Err(VerifyError::InvalidArgCount { addr, argc: operands[1] });
+            }
+            OpCode::ENTER if operands[0] < 0 => {
+                return Err(VerifyError::InvalidFrameSize { addr, size: operands[0] });
+            }
+            _ => {}
+        }
+        instructions.insert(addr, (opcode, operands));
+        addr += 1 + operands.len();
+    }
+    for (addr, (opcode, operands)) in &instructions {
+        if let OpCode::JMP | OpCode::JMP0 | OpCode::JMP1 | OpCode::CALL = opcode {
+            let target = operands[0];
+            if usize::try_from(target).map_or(true, |target| !instructions.contains_key(&target)) {
+                return Err(VerifyError::InvalidJump { addr: *addr, opcode: *opcode, target });
+            }
+        }
+    }
+    Ok(instructions)
+}
+
+// How many values an instruction pops from the stack, and how many it pushes
+fn stack_effect(opcode: OpCode, operands: &[i32]) -> (usize, usize) {
+    match opcode {
+        OpCode::PUSH
+        | OpCode::GLOAD
+        | OpCode::LLOAD
+        | OpCode::NIL
+        | OpCode::TRUE
+        | OpCode::FALSE
+        | OpCode::CONST
+        | OpCode::GC => (0, 1),
+        // The locals of the call frame
+        OpCode::ENTER => (0, operands[0] as usize),
+        OpCode::GSTORE
+        | OpCode::LSTORE
+        | OpCode::POP
+        | OpCode::PRINT
+        | OpCode::JMP0
+        | OpCode::JMP1
+        | OpCode::RET => (1, 0),
+        OpCode::LEN | OpCode::STR | OpCode::NEG | OpCode::NOT | OpCode::BNOT => (1, 1),
+        OpCode::DUP => (1, 2),
+        // The string, then the index or the start and the end of the slice
+        // on top of it
+        OpCode::INDEX => (2, 1),
+        OpCode::SLICE => (3, 1),
+        OpCode::ADD
+        | OpCode::SUB
+        | OpCode::MUL
+        | OpCode::DIV
+        | OpCode::MOD
+        | OpCode::POW
+        | OpCode::BAND
+        | OpCode::BOR
+        | OpCode::BXOR
+        | OpCode::SHL
+        | OpCode::SHR
+        | OpCode::EQ
+        | OpCode::NE
+        | OpCode::GT
+        | OpCode::LT
+        | OpCode::GE
+        | OpCode::LE => (2, 1),
+        // The arguments are replaced by the return value
+        OpCode::CALL => (operands[1] as usize, 1),
+        OpCode::HALT | OpCode::JMP => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, VerifyError};
+    use crate::bytecode::{Constant, OpCode, Program, FUNC_PARAM_OFFSET};
+
+    fn check(code: Vec<i32>, entrypoint: usize) -> Result<(), VerifyError> {
+        verify(&Program { code, entrypoint, constants: vec![Constant::Int(1 << 40)], spans: vec![] })
+    }
+
+    #[test]
+    fn verify_function_call() {
+        let actual = check(vec![
+            // fn add(a, b) -> a + b
+            OpCode::LLOAD as i32, -(FUNC_PARAM_OFFSET + 2),   // 000
+            OpCode::LLOAD as i32, -(FUNC_PARAM_OFFSET + 1),   // 002
+            OpCode::ADD as i32,                               // 004
+            OpCode::RET as i32,                               // 005
+            OpCode::PUSH as i32, 1,                           // 006
+            OpCode::PUSH as i32, 2,                           // 008
+            OpCode::CALL as i32, 0, 2,                        // 010
+            OpCode::PRINT as i32,                             // 013
+            OpCode::HALT as i32,                              // 014
+        ], 6);
+        assert_eq!(actual, Ok(()));
+    }
+
+    #[test]
+    fn verify_decoding() {
+        assert_eq!(
+            check(vec![OpCode::PUSH as i32, 1, 99], 0),
+            Err(VerifyError::InvalidOpcode { addr: 2, value: 99 }),
+        );
+        assert_eq!(
+            check(vec![OpCode::HALT as i32, OpCode::CALL as i32, 0], 0),
+            Err(VerifyError::MissingOperand { addr: 1, opcode: OpCode::CALL }),
+        );
+        assert_eq!(
+            check(vec![OpCode::PUSH as i32, 1, OpCode::HALT as i32], 1),
+            Err(VerifyError::InvalidEntrypoint { entrypoint: 1 }),
+        );
+        // Into the middle of the PUSH
+        assert_eq!(
+            check(vec![OpCode::PUSH as i32, 1, OpCode::JMP as i32, 1], 0),
+            Err(VerifyError::InvalidJump { addr: 2, opcode: OpCode::JMP, target: 1 }),
+        );
+        assert_eq!(
+            check(vec![OpCode::CONST as i32, 0, OpCode::CONST as i32, 1, OpCode::HALT as i32], 0),
+            Err(VerifyError::InvalidConstant { addr: 2, index: 1 }),
+        );
+        assert_eq!(
+            check(vec![OpCode::PUSH as i32, 1, OpCode::GSTORE as i32, -1, OpCode::HALT as i32], 0),
+            Err(VerifyError::InvalidGlobal { addr: 2, opcode: OpCode::GSTORE, slot: -1 }),
+        );
+    }
+
+    #[test]
+    fn verify_control_flow() {
+        assert_eq!(
+            check(vec![OpCode::PUSH as i32, 1, OpCode::PRINT as i32], 0),
+            Err(VerifyError::FallsOffEnd { addr: 2, opcode: OpCode::PRINT }),
+        );
+        assert_eq!(
+            check(vec![OpCode::PUSH as i32, 1, OpCode::RET as i32], 0),
+            Err(VerifyError::ReturnOutsideFunction { addr: 2 }),
+        );
+        assert_eq!(
+            check(vec![OpCode::PUSH as i32, 1, OpCode::ADD as i32, OpCode::HALT as i32], 0),
+            Err(VerifyError::StackUnderflow { addr: 2, opcode: OpCode::ADD }),
+        );
+    }
+
+    #[test]
+    fn verify_stack_depth() {
+        // A loop that pushes one more value every time around
+        assert_eq!(
+            check(vec![
+                OpCode::PUSH as i32, 1,                 // 000
+                OpCode::JMP as i32, 0,                  // 002
+            ], 0),
+            Err(VerifyError::InconsistentStack { addr: 0, expected: 0, found: 1 }),
+        );
+        // Only one branch pushes a value before they join
+        assert_eq!(
+            check(vec![
+                OpCode::PUSH as i32, 1,                 // 000
+                OpCode::JMP0 as i32, 6,                 // 002
+                OpCode::PUSH as i32, 2,                 // 004
+                OpCode::HALT as i32,                    // 006
+            ], 0),
+            Err(VerifyError::InconsistentStack { addr: 6, expected: 1, found: 0 }),
+        );
+    }
+
+    #[test]
+    fn verify_locals() {
+        // Reading the return address saved by CALL
+        assert_eq!(
+            check(vec![
+                OpCode::LLOAD as i32, -2,               // 000
+                OpCode::RET as i32,                     // 002
+                OpCode::CALL as i32, 0, 0,              // 003
+                OpCode::HALT as i32,                    // 006
+            ], 3),
+            Err(VerifyError::InvalidLocal { addr: 0, opcode: OpCode::LLOAD, offset: -2 }),
+        );
+        // A local slot that was never pushed
+        assert_eq!(
+            check(vec![OpCode::LLOAD as i32, 0, OpCode::HALT as i32], 0),
+            Err(VerifyError::InvalidLocal { addr: 0, opcode: OpCode::LLOAD, offset: 0 }),
+        );
+        // The slots reserved by ENTER
+        assert_eq!(
+            check(vec![
+                OpCode::ENTER as i32, 2,                // 000
+                OpCode::LLOAD as i32, 1,                // 002
+                OpCode::LSTORE as i32, 0,               // 004
+                OpCode::LLOAD as i32, 2,                // 006
+                OpCode::HALT as i32,                    // 008
+            ], 0),
+            Err(VerifyError::InvalidLocal { addr: 6, opcode: OpCode::LLOAD, offset: 2 }),
+        );
+        assert_eq!(
+            check(vec![OpCode::ENTER as i32, -1, OpCode::HALT as i32], 0),
+            Err(VerifyError::InvalidFrameSize { addr: 0, size: -1 }),
+        );
+    }
+}
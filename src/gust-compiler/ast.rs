@@ -0,0 +1,152 @@
+use std::borrow::Cow;
+use super::token::Span;
+
+// The abstract syntax tree produced by the Parser.
+//
+// Names and string literals borrow from the original source, just like
+// the tokens do, so building the tree doesn't allocate a new string for
+// every identifier. Only the strings with escapes own their value.
+//
+// Every statement and expression knows where it came from in the source,
+// so the compiler can point at it when something is wrong. The span is
+// not part of the equality though: two nodes parsed from different places
+// are still the same node.
+
+#[derive(Debug, Clone)]
+pub struct Stmt<'a> {
+    pub kind: StmtKind<'a>,
+    pub span: Span,
+    // The lines of the `///` comments before a declaration
+    pub docs: Vec<&'a str>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind<'a> {
+    Let {
+        name: &'a str,
+        value: Expr<'a>,
+    },
+    Assign {
+        name: &'a str,
+        value: Expr<'a>,
+    },
+    Print(Expr<'a>),
+    Expr(Expr<'a>),
+    If {
+        cond: Expr<'a>,
+        then_branch: Vec<Stmt<'a>>,
+        else_branch: Option<Vec<Stmt<'a>>>,
+    },
+    While {
+        cond: Expr<'a>,
+        body: Vec<Stmt<'a>>,
+    },
+    For {
+        var: &'a str,
+        iterable: Expr<'a>,
+        body: Vec<Stmt<'a>>,
+    },
+    Break,
+    Continue,
+    Func {
+        name: &'a str,
+        params: Vec<&'a str>,
+        body: Vec<Stmt<'a>>,
+    },
+    Return(Option<Expr<'a>>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr<'a> {
+    pub kind: ExprKind<'a>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind<'a> {
+    Int(i64),
+    Float(f64),
+    // The value of a string literal, without the surrounding quotes
+    String(Cow<'a, str>),
+    // "Hello ${name}!": the string parts and the expressions, in order
+    Interpolation(Vec<Expr<'a>>),
+    Bool(bool),
+    Nil,
+    Variable(&'a str),
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr<'a>>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr<'a>>,
+        right: Box<Expr<'a>>,
+    },
+    Range {
+        start: Box<Expr<'a>>,
+        end: Box<Expr<'a>>,
+    },
+    Call {
+        callee: &'a str,
+        args: Vec<Expr<'a>>,
+    },
+    // `target[index]`, or a slice when the index is a Range
+    Index {
+        target: Box<Expr<'a>>,
+        index: Box<Expr<'a>>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    And,
+    Or,
+}
+
+impl<'a> Stmt<'a> {
+    pub fn new(kind: StmtKind<'a>, span: Span) -> Self {
+        Self { kind, span, docs: vec![] }
+    }
+}
+
+impl<'a> PartialEq for Stmt<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl<'a> Expr<'a> {
+    pub fn new(kind: ExprKind<'a>, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+impl<'a> PartialEq for Expr<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
@@ -0,0 +1,1422 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt,
+    rc::Rc,
+};
+use crate::bytecode::{span_at, Constant, OpCode, Program};
+use crate::diagnostics::Diagnostic;
+use super::ast::{BinaryOp, Expr, ExprKind, Stmt, StmtKind, UnaryOp};
+use super::resolver::{Resolver, Slot};
+use super::token::Span;
+
+// The code generator walks the AST and emits the i32 program that the
+// VirtualMachine executes.
+//
+// A compiled program is laid out like this:
+//
+//   [fn declarations...][top-level statements...][HALT]
+//                        ^ entrypoint
+//
+// Functions are hoisted, so a function can be called before (or from
+// inside) its own declaration. Calls to a function that has not been
+// emitted yet are back-patched once all functions are compiled.
+//
+// The Resolver gives every variable its slot: the top-level `let`s live in
+// the `globals` slots of the VM, everything declared in a block or in a
+// function is read relative to the frame pointer. The parameters are below
+// the 3 values pushed by CALL:
+//
+//   fp - FUNC_PARAM_OFFSET - argc + i   ->  parameter i
+//   fp + n                              ->  local variable n
+//
+// The function prologue `ENTER n` reserves the n local slots of the frame,
+// set to nil, so the values pushed while evaluating expressions never
+// overlap the locals. n is only known once the body is compiled, from the
+// Resolver, so the operand is patched afterwards. The top-level code does the same for the locals of
+// its blocks, and pops them before HALT, so the next chunk starts with an
+// empty stack again.
+//
+// A `for` loop keeps its state in hidden variables next to the loop
+// variable, in a scope around the body: the end of a range, or the value
+// being iterated and the current index. Their names can't be written in
+// the source.
+//
+// Integers that fit in an i32 are pushed with PUSH, the other literals are
+// interned in the constant pool and loaded with CONST. The pool also has
+// an entry for every function, for the tools that read the program.
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    UndefinedVariable {
+        name: String,
+        span: Span,
+    },
+    UndefinedFunction {
+        name: String,
+        span: Span,
+    },
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    DuplicateFunction {
+        name: String,
+        span: Span,
+    },
+    NestedFunction {
+        name: String,
+        span: Span,
+    },
+    UsedBeforeDeclaration {
+        name: String,
+        span: Span,
+    },
+    DuplicateVariable {
+        name: String,
+        span: Span,
+    },
+    TooManyGlobals {
+        limit: usize,
+        span: Span,
+    },
+    ReturnOutsideFunction {
+        span: Span,
+    },
+    // `break` or `continue`
+    BreakOutsideLoop {
+        keyword: &'static str,
+        span: Span,
+    },
+    Unsupported {
+        what: &'static str,
+        span: Span,
+    },
+}
+
+impl CompileError {
+    pub fn span(&self) -> Span {
+        match self {
+            CompileError::UndefinedVariable { span, .. }
+            | CompileError::UndefinedFunction { span, .. }
+            | CompileError::ArityMismatch { span, .. }
+            | CompileError::DuplicateFunction { span, .. }
+            | CompileError::NestedFunction { span, .. }
+            | CompileError::UsedBeforeDeclaration { span, .. }
+            | CompileError::DuplicateVariable { span, .. }
+            | CompileError::TooManyGlobals { span, .. }
+            | CompileError::ReturnOutsideFunction { span }
+            | CompileError::BreakOutsideLoop { span, .. }
+            | CompileError::Unsupported { span, .. } => *span,
+        }
+    }
+
+    // The error message, without the position
+    pub fn message(&self) -> String {
+        match self {
+            CompileError::UndefinedVariable { name, .. } => format!("undefined variable `{}`", name),
+            CompileError::UndefinedFunction { name, .. } => format!("undefined function `{}`", name),
+            CompileError::ArityMismatch { name, expected, found, .. } => format!(
+                "function `{}` takes {} argument(s) but {} were given",
+                name, expected, found
+            ),
+            CompileError::DuplicateFunction { name, .. } => {
+                format!("function `{}` is declared more than once", name)
+            }
+            CompileError::NestedFunction { name, .. } => {
+                format!("function `{}` must be declared at the top level", name)
+            }
+            CompileError::UsedBeforeDeclaration { name, .. } => {
+                format!("variable `{}` is used before its declaration", name)
+            }
+            CompileError::DuplicateVariable { name, .. } => {
+                format!("variable `{}` is declared more than once in the same block", name)
+            }
+            CompileError::TooManyGlobals { limit, .. } => {
+                format!("a program can't declare more than {} global variables", limit)
+            }
+            CompileError::ReturnOutsideFunction { .. } => "`return` outside of a function".to_string(),
+            CompileError::BreakOutsideLoop { keyword, .. } => format!("`{}` outside of a loop", keyword),
+            CompileError::Unsupported { what, .. } => format!("{} are not supported yet", what),
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let diagnostic = match self {
+            CompileError::UndefinedVariable { name, .. } => Diagnostic::error("E0201", self.message())
+                .with_help(format!("declare it first with `let {} = ...`", name)),
+            CompileError::UndefinedFunction { .. } => Diagnostic::error("E0202", self.message()),
+            CompileError::ArityMismatch { name, expected, .. } => Diagnostic::error("E0203", self.message())
+                .with_help(format!("`{}` is declared with {} parameter(s)", name, expected)),
+            CompileError::DuplicateFunction { .. } => Diagnostic::error("E0204", self.message()),
+            CompileError::NestedFunction { .. } => Diagnostic::error("E0205", self.message())
+                .with_help("move it out of the enclosing function"),
+            CompileError::ReturnOutsideFunction { .. } => Diagnostic::error("E0206", self.message()),
+            CompileError::BreakOutsideLoop { .. } => Diagnostic::error("E0208", self.message()),
+            CompileError::UsedBeforeDeclaration { .. } => Diagnostic::error("E0209", self.message())
+                .with_help("move the `let` before the first use"),
+            CompileError::DuplicateVariable { name, .. } => Diagnostic::error("E0210", self.message())
+                .with_help(format!("use `{} = ...` to assign a new value", name)),
+            CompileError::TooManyGlobals { .. } => Diagnostic::error("E0211", self.message())
+                .with_help("move some of them into a block or a function"),
+            CompileError::Unsupported { .. } => Diagnostic::error("E0207", self.message()),
+        };
+        diagnostic.with_span(self.span())
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        write!(f, "{}:{}: {}", span.line, span.column, self.message())
+    }
+}
+
+pub type CompileResult<T> = Result<T, CompileError>;
+
+#[derive(Clone, Copy)]
+struct Function {
+    addr: Option<usize>,
+    arity: usize,
+}
+
+// The jumps out of the loop being compiled, patched once its end is known
+#[derive(Default)]
+struct Loop {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// The CodeGenerator keeps the globals and functions it has seen, so it
+// can compile a program in multiple chunks (like the REPL does, one
+// line at a time) and every chunk can use what the previous ones declared.
+pub struct CodeGenerator {
+    code: Vec<i32>,
+    // Every literal is stored once, no matter how many times it is used
+    constants: Vec<Constant>,
+    resolver: Resolver,
+    functions: HashMap<String, Function>,
+    // Call sites waiting for the address of a function: (operand index, name)
+    patches: Vec<(usize, String)>,
+    // The loops around the statement being compiled, the innermost last
+    loops: Vec<Loop>,
+    // The source map of the code, and the span of the node being compiled
+    spans: Vec<(usize, Span)>,
+    span: Span,
+}
+
+impl CodeGenerator {
+    pub fn new() -> Self {
+        Self {
+            code: vec![],
+            constants: vec![],
+            resolver: Resolver::new(),
+            functions: HashMap::new(),
+            patches: vec![],
+            loops: vec![],
+            spans: vec![],
+            span: Span::default(),
+        }
+    }
+
+    pub fn compile(mut self, program: &[Stmt]) -> CompileResult<Program> {
+        let entrypoint = self.compile_chunk(program)?;
+        Ok(Program {
+            code: self.code,
+            entrypoint,
+            constants: self.constants,
+            spans: self.spans,
+        })
+    }
+
+    // All the code generated so far, for every chunk
+    pub fn code(&self) -> &[i32] {
+        &self.code
+    }
+
+    // The constant pool of every chunk
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    // The source of the instruction at `ip`
+    pub fn span_at(&self, ip: usize) -> Option<Span> {
+        span_at(&self.spans, ip)
+    }
+
+    // Compile a program and append it to the code generated so far,
+    // returns the entrypoint of the new chunk. If the chunk doesn't
+    // compile, the generator is left as it was before the call.
+    pub fn compile_chunk(&mut self, program: &[Stmt]) -> CompileResult<usize> {
+        let code_len = self.code.len();
+        let constants_len = self.constants.len();
+        let resolver = self.resolver.clone();
+        let functions = self.functions.clone();
+        let result = self.chunk(program);
+        if result.is_err() {
+            self.code.truncate(code_len);
+            self.constants.truncate(constants_len);
+            self.spans.retain(|(addr, _)| *addr < code_len);
+            self.resolver = resolver;
+            self.functions = functions;
+            self.patches.clear();
+            self.loops.clear();
+        }
+        result
+    }
+
+    fn chunk(&mut self, program: &[Stmt]) -> CompileResult<usize> {
+        // The globals get their slots first, the functions can use them
+        self.resolver.begin_chunk(program)?;
+        let mut declared = HashSet::new();
+        for stmt in program {
+            if let StmtKind::Func { name, params, .. } = &stmt.kind {
+                if !declared.insert(*name) {
+                    return Err(CompileError::DuplicateFunction {
+                        name: name.to_string(),
+                        span: stmt.span,
+                    });
+                }
+                self.functions.insert(name.to_string(), Function { addr: None, arity: params.len() });
+            }
+        }
+        for stmt in program {
+            if let StmtKind::Func { name, params, body } = &stmt.kind {
+                self.span = stmt.span;
+                let addr = self.code.len();
+                self.function(name, params, body)?;
+                self.constants.push(Constant::Function {
+                    name: Rc::from(*name),
+                    addr,
+                    arity: params.len(),
+                });
+            }
+        }
+        for (index, name) in self.patches.drain(..) {
+            if let Some(Function { addr: Some(addr), .. }) = self.functions.get(&name) {
+                self.code[index] = *addr as i32;
+            }
+        }
+        let entrypoint = self.code.len();
+        let statements = program
+            .iter()
+            .filter(|stmt| !matches!(stmt.kind, StmtKind::Func { .. }))
+            .collect::<Vec<_>>();
+        // The top-level `let`s are globals, only the blocks need a frame.
+        // The prologue belongs to the first statement and the epilogue to
+        // the last one, so an error there still points at the source.
+        if let Some(stmt) = statements.first() {
+            self.span = stmt.span;
+        }
+        let enter = self.emit_enter();
+        for stmt in &statements {
+            self.statement(stmt)?;
+        }
+        if let Some(stmt) = statements.last() {
+            self.span = stmt.span;
+        }
+        let frame_size = self.resolver.frame_size();
+        self.code[enter] = frame_size as i32;
+        for _ in 0..frame_size {
+            self.emit(OpCode::POP);
+        }
+        self.emit(OpCode::HALT);
+        Ok(entrypoint)
+    }
+
+    fn function(&mut self, name: &str, params: &[&str], body: &[Stmt]) -> CompileResult<()> {
+        let addr = self.code.len();
+        if let Some(function) = self.functions.get_mut(name) {
+            function.addr = Some(addr);
+        }
+        let enter = self.emit_enter();
+        self.resolver.begin_function(params, body, self.span)?;
+        for stmt in body {
+            self.statement(stmt)?;
+        }
+        // Functions without a `return` at the end return nil
+        self.emit(OpCode::NIL);
+        self.emit(OpCode::RET);
+        self.code[enter] = self.resolver.frame_size() as i32;
+        self.resolver.end_function();
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> CompileResult<()> {
+        let outer = std::mem::replace(&mut self.span, stmt.span);
+        self.statement_kind(stmt)?;
+        self.span = outer;
+        Ok(())
+    }
+
+    fn statement_kind(&mut self, stmt: &Stmt) -> CompileResult<()> {
+        match &stmt.kind {
+            StmtKind::Let { name, value } => {
+                // The value can still use a variable with the same name
+                // from an enclosing scope
+                self.expression(value)?;
+                let slot = self.resolver.declare(name, stmt.span)?;
+                self.emit_store(slot);
+            }
+            StmtKind::Assign { name, value } => {
+                self.expression(value)?;
+                let slot = self.resolver.resolve(name, stmt.span)?;
+                self.emit_store(slot);
+            }
+            StmtKind::Print(value) => {
+                self.expression(value)?;
+                self.emit(OpCode::PRINT);
+            }
+            StmtKind::Expr(expr) => {
+                self.expression(expr)?;
+                self.emit(OpCode::POP);
+            }
+            StmtKind::If { cond, then_branch, else_branch } => {
+                self.expression(cond)?;
+                let jump_to_else = self.emit_jump(OpCode::JMP0);
+                self.block(then_branch)?;
+                if let Some(else_branch) = else_branch {
+                    let jump_to_end = self.emit_jump(OpCode::JMP);
+                    self.patch_jump(jump_to_else);
+                    self.block(else_branch)?;
+                    self.patch_jump(jump_to_end);
+                } else {
+                    self.patch_jump(jump_to_else);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                // start:
+                //     [cond] JMP0 end
+                //     [body] JMP start
+                // end:
+                let start = self.code.len();
+                self.expression(cond)?;
+                let jump_to_end = self.emit_jump(OpCode::JMP0);
+                let exits = self.loop_body(body)?;
+                for continue_jump in exits.continues {
+                    self.code[continue_jump] = start as i32;
+                }
+                self.emit_with(OpCode::JMP, &[start as i32]);
+                self.patch_jump(jump_to_end);
+                for break_jump in exits.breaks {
+                    self.patch_jump(break_jump);
+                }
+            }
+            StmtKind::For { var, iterable, body } => self.for_loop(var, iterable, body, stmt.span)?,
+            StmtKind::Break | StmtKind::Continue => {
+                let is_break = stmt.kind == StmtKind::Break;
+                let jump = self.emit_jump(OpCode::JMP);
+                match self.loops.last_mut() {
+                    Some(exits) if is_break => exits.breaks.push(jump),
+                    Some(exits) => exits.continues.push(jump),
+                    None => return Err(CompileError::BreakOutsideLoop {
+                        keyword: if is_break { "break" } else { "continue" },
+                        span: stmt.span,
+                    }),
+                }
+            }
+            StmtKind::Func { name, .. } => return Err(CompileError::NestedFunction {
+                name: name.to_string(),
+                span: stmt.span,
+            }),
+            StmtKind::Return(value) => {
+                if !self.resolver.in_function() {
+                    return Err(CompileError::ReturnOutsideFunction { span: stmt.span });
+                }
+                match value {
+                    Some(value) => self.expression(value)?,
+                    None => self.emit(OpCode::NIL),
+                }
+                self.emit(OpCode::RET);
+            }
+        }
+        Ok(())
+    }
+
+    // The loop variable and the hidden variables are declared in a scope
+    // around the body, after the iterable is evaluated
+    fn for_loop(&mut self, var: &str, iterable: &Expr, body: &[Stmt], span: Span) -> CompileResult<()> {
+        self.resolver.begin_block(&[]);
+        let (counter, condition, jump_to_end) = if let ExprKind::Range { start, end } = &iterable.kind {
+            //     [start] [end] STORE end STORE var
+            // start:
+            //     [var] [end] LT JMP0 end
+            //     [body]
+            // continue:
+            //     [var] PUSH 1 ADD STORE var
+            //     JMP start
+            // end:
+            self.expression(start)?;
+            self.expression(end)?;
+            let end = self.resolver.declare("#end", span)?;
+            let var = self.resolver.declare(var, span)?;
+            self.emit_store(end);
+            self.emit_store(var);
+            let condition = self.code.len();
+            self.emit_load(var);
+            self.emit_load(end);
+            self.emit(OpCode::LT);
+            let jump_to_end = self.emit_jump(OpCode::JMP0);
+            (var, condition, jump_to_end)
+        } else {
+            // Anything that LEN and INDEX work on:
+            //     [iterable] STORE iter
+            //     PUSH 0 STORE index
+            // start:
+            //     [index] [iter] LEN LT JMP0 end
+            //     [iter] [index] INDEX STORE var
+            //     [body]
+            // continue:
+            //     [index] PUSH 1 ADD STORE index
+            //     JMP start
+            // end:
+            self.expression(iterable)?;
+            let iter = self.resolver.declare("#iter", span)?;
+            let index = self.resolver.declare("#index", span)?;
+            let var = self.resolver.declare(var, span)?;
+            self.emit_store(iter);
+            self.emit_with(OpCode::PUSH, &[0]);
+            self.emit_store(index);
+            let condition = self.code.len();
+            self.emit_load(index);
+            self.emit_load(iter);
+            self.emit(OpCode::LEN);
+            self.emit(OpCode::LT);
+            let jump_to_end = self.emit_jump(OpCode::JMP0);
+            self.emit_load(iter);
+            self.emit_load(index);
+            self.emit(OpCode::INDEX);
+            self.emit_store(var);
+            (index, condition, jump_to_end)
+        };
+        let exits = self.loop_body(body)?;
+        for continue_jump in exits.continues {
+            self.patch_jump(continue_jump);
+        }
+        self.emit_load(counter);
+        self.emit_with(OpCode::PUSH, &[1]);
+        self.emit(OpCode::ADD);
+        self.emit_store(counter);
+        self.emit_with(OpCode::JMP, &[condition as i32]);
+        self.patch_jump(jump_to_end);
+        for break_jump in exits.breaks {
+            self.patch_jump(break_jump);
+        }
+        self.resolver.end_block();
+        Ok(())
+    }
+
+    // Compile the body of a loop, returns its `break` and `continue` jumps
+    fn loop_body(&mut self, body: &[Stmt]) -> CompileResult<Loop> {
+        self.loops.push(Loop::default());
+        let result = self.block(body);
+        let exits = self.loops.pop().unwrap_or_default();
+        result.map(|_| exits)
+    }
+
+    // The statements of a `{}` block, in a new scope
+    fn block(&mut self, body: &[Stmt]) -> CompileResult<()> {
+        self.resolver.begin_block(body);
+        let result = body.iter().try_for_each(|stmt| self.statement(stmt));
+        self.resolver.end_block();
+        result
+    }
+
+    fn expression(&mut self, expr: &Expr) -> CompileResult<()> {
+        let outer = std::mem::replace(&mut self.span, expr.span);
+        self.expression_kind(expr)?;
+        self.span = outer;
+        Ok(())
+    }
+
+    fn expression_kind(&mut self, expr: &Expr) -> CompileResult<()> {
+        let unsupported = |what| CompileError::Unsupported { what, span: expr.span };
+        match &expr.kind {
+            ExprKind::Int(n) => match i32::try_from(*n) {
+                Ok(n) => self.emit_with(OpCode::PUSH, &[n]),
+                Err(_) => self.emit_constant(Constant::Int(*n)),
+            },
+            ExprKind::Float(n) => self.emit_constant(Constant::Float(*n)),
+            ExprKind::String(s) => self.emit_constant(Constant::String(Rc::from(&**s))),
+            ExprKind::Interpolation(parts) => {
+                // "a${b}c" is ("a" + str(b)) + "c", the parts are evaluated
+                // from the left like the operands of the other binary
+                // operators. The empty strings are left out, unless
+                // nothing else is left.
+                let parts = parts
+                    .iter()
+                    .filter(|part| !matches!(&part.kind, ExprKind::String(s) if s.is_empty()))
+                    .collect::<Vec<_>>();
+                if parts.is_empty() {
+                    self.emit_constant(Constant::String(Rc::from("")));
+                }
+                for (i, part) in parts.iter().enumerate() {
+                    self.expression(part)?;
+                    if !matches!(part.kind, ExprKind::String(_)) {
+                        self.emit(OpCode::STR);
+                    }
+                    if i > 0 {
+                        self.emit(OpCode::ADD);
+                    }
+                }
+            }
+            ExprKind::Bool(true) => self.emit(OpCode::TRUE),
+            ExprKind::Bool(false) => self.emit(OpCode::FALSE),
+            ExprKind::Nil => self.emit(OpCode::NIL),
+            ExprKind::Range { .. } => return Err(unsupported("ranges outside of `for` loops")),
+            ExprKind::Variable(name) => self.load(name, expr.span)?,
+            ExprKind::Unary { op, expr } => {
+                self.expression(expr)?;
+                self.emit(match op {
+                    UnaryOp::Neg => OpCode::NEG,
+                    UnaryOp::Not => OpCode::NOT,
+                    UnaryOp::BitNot => OpCode::BNOT,
+                });
+            }
+            // The logical operators evaluate to the operand that decided the
+            // result, and only evaluate `right` when `left` didn't
+            ExprKind::Binary { op: BinaryOp::And, left, right } => {
+                // left && right:
+                //     [left] DUP JMP0 end
+                //     POP [right]
+                // end:
+                self.expression(left)?;
+                self.emit(OpCode::DUP);
+                let jump_to_end = self.emit_jump(OpCode::JMP0);
+                self.emit(OpCode::POP);
+                self.expression(right)?;
+                self.patch_jump(jump_to_end);
+            }
+            ExprKind::Binary { op: BinaryOp::Or, left, right } => {
+                // left || right:
+                //     [left] DUP JMP1 end
+                //     POP [right]
+                // end:
+                self.expression(left)?;
+                self.emit(OpCode::DUP);
+                let jump_to_end = self.emit_jump(OpCode::JMP1);
+                self.emit(OpCode::POP);
+                self.expression(right)?;
+                self.patch_jump(jump_to_end);
+            }
+            ExprKind::Binary { op, left, right } => {
+                self.expression(left)?;
+                self.expression(right)?;
+                self.emit(match op {
+                    BinaryOp::Add => OpCode::ADD,
+                    BinaryOp::Sub => OpCode::SUB,
+                    BinaryOp::Mul => OpCode::MUL,
+                    BinaryOp::Div => OpCode::DIV,
+                    BinaryOp::Mod => OpCode::MOD,
+                    BinaryOp::Pow => OpCode::POW,
+                    BinaryOp::BitAnd => OpCode::BAND,
+                    BinaryOp::BitOr => OpCode::BOR,
+                    BinaryOp::BitXor => OpCode::BXOR,
+                    BinaryOp::Shl => OpCode::SHL,
+                    BinaryOp::Shr => OpCode::SHR,
+                    BinaryOp::Eq => OpCode::EQ,
+                    BinaryOp::Ne => OpCode::NE,
+                    BinaryOp::Gt => OpCode::GT,
+                    BinaryOp::Lt => OpCode::LT,
+                    BinaryOp::Ge => OpCode::GE,
+                    BinaryOp::Le => OpCode::LE,
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                });
+            }
+            ExprKind::Index { target, index } => {
+                if let ExprKind::Range { start, end } = &index.kind {
+                    // target[start..end]: [target] [start] [end] SLICE
+                    self.expression(target)?;
+                    self.expression(start)?;
+                    self.expression(end)?;
+                    self.emit(OpCode::SLICE);
+                } else {
+                    self.expression(target)?;
+                    self.expression(index)?;
+                    self.emit(OpCode::INDEX);
+                }
+            }
+            ExprKind::Call { callee, args } => match builtin(callee) {
+                // A function of the program replaces the builtin with the
+                // same name
+                Some((opcode, arity)) if !self.functions.contains_key(*callee) => {
+                    if arity != args.len() {
+                        return Err(CompileError::ArityMismatch {
+                            name: callee.to_string(),
+                            expected: arity,
+                            found: args.len(),
+                            span: expr.span,
+                        });
+                    }
+                    for arg in args {
+                        self.expression(arg)?;
+                    }
+                    self.emit(opcode);
+                }
+                _ => self.call(callee, args, expr.span)?,
+            },
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, callee: &str, args: &[Expr], span: Span) -> CompileResult<()> {
+        let function = match self.functions.get(callee) {
+            Some(function) => *function,
+            None => return Err(CompileError::UndefinedFunction {
+                name: callee.to_string(),
+                span,
+            }),
+        };
+        if function.arity != args.len() {
+            return Err(CompileError::ArityMismatch {
+                name: callee.to_string(),
+                expected: function.arity,
+                found: args.len(),
+                span,
+            });
+        }
+        for arg in args {
+            self.expression(arg)?;
+        }
+        let addr = match function.addr {
+            Some(addr) => addr as i32,
+            None => {
+                self.patches.push((self.code.len() + 1, callee.to_string()));
+                0
+            }
+        };
+        self.emit_with(OpCode::CALL, &[addr, args.len() as i32]);
+        Ok(())
+    }
+
+    fn load(&mut self, name: &str, span: Span) -> CompileResult<()> {
+        let slot = self.resolver.resolve(name, span)?;
+        self.emit_load(slot);
+        Ok(())
+    }
+
+    fn emit_load(&mut self, slot: Slot) {
+        match slot {
+            Slot::Global(slot) => self.emit_with(OpCode::GLOAD, &[slot]),
+            Slot::Local(offset) => self.emit_with(OpCode::LLOAD, &[offset]),
+        }
+    }
+
+    fn emit_store(&mut self, slot: Slot) {
+        match slot {
+            Slot::Global(slot) => self.emit_with(OpCode::GSTORE, &[slot]),
+            Slot::Local(offset) => self.emit_with(OpCode::LSTORE, &[offset]),
+        }
+    }
+
+    fn emit(&mut self, opcode: OpCode) {
+        if self.spans.last().map(|(_, span)| *span) != Some(self.span) {
+            self.spans.push((self.code.len(), self.span));
+        }
+        self.code.push(opcode as i32);
+    }
+
+    fn emit_with(&mut self, opcode: OpCode, operands: &[i32]) {
+        self.emit(opcode);
+        self.code.extend_from_slice(operands);
+    }
+
+    fn emit_constant(&mut self, constant: Constant) {
+        let index = match self.constants.iter().position(|known| *known == constant) {
+            Some(index) => index,
+            None => {
+                self.constants.push(constant);
+                self.constants.len() - 1
+            }
+        };
+        self.emit_with(OpCode::CONST, &[index as i32]);
+    }
+
+    // Emit an ENTER with a placeholder frame size, returns the index of the
+    // operand so it can be patched once the size is known
+    fn emit_enter(&mut self) -> usize {
+        self.emit_with(OpCode::ENTER, &[0]);
+        self.code.len() - 1
+    }
+
+    // Emit a jump with a placeholder target, returns the index of the
+    // operand so it can be patched later
+    fn emit_jump(&mut self, opcode: OpCode) -> usize {
+        self.emit_with(opcode, &[0]);
+        self.code.len() - 1
+    }
+
+    // Point a previously emitted jump to the next instruction
+    fn patch_jump(&mut self, operand: usize) {
+        self.code[operand] = self.code.len() as i32;
+    }
+}
+
+pub fn compile(program: &[Stmt]) -> CompileResult<Program> {
+    CodeGenerator::new().compile(program)
+}
+
+// The functions implemented by an opcode, and their arity
+fn builtin(name: &str) -> Option<(OpCode, usize)> {
+    match name {
+        "len" => Some((OpCode::LEN, 1)),
+        "gc" => Some((OpCode::GC, 0)),
+        "str" => Some((OpCode::STR, 1)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, CompileError};
+    use crate::bytecode::{Constant, OpCode, Program};
+    use crate::compiler::{lexer::Lexer, parser::Parser, token::Span};
+    use crate::vm::{heap::GcConfig, VirtualMachine, VmError};
+
+    fn compile_source(source: &str) -> Result<Program, CompileError> {
+        let program = Parser::new(Lexer::new(source)).parse().unwrap();
+        compile(&program)
+    }
+
+    fn run(source: &str) -> String {
+        let program = compile_source(source).unwrap();
+        let mut stdout = vec![];
+        let mut vm = VirtualMachine::new();
+        vm.load_verified(&program).unwrap();
+        vm.run(&mut stdout).unwrap();
+        String::from_utf8(stdout).unwrap()
+    }
+
+    #[test]
+    fn codegen_simple_program_test() {
+        let program = compile_source("print(10 + 5)").unwrap();
+        assert_eq!(program.entrypoint, 0);
+        assert_eq!(program.code, vec![
+            OpCode::ENTER as i32, 0,
+            OpCode::PUSH as i32, 10,
+            OpCode::PUSH as i32, 5,
+            OpCode::ADD as i32,
+            OpCode::PRINT as i32,
+            OpCode::HALT as i32,
+        ]);
+    }
+
+    #[test]
+    fn codegen_if_jump_patching_test() {
+        let program = compile_source(r#"if 1 {
+            print(1)
+        } else {
+            print(0)
+        }"#).unwrap();
+        assert_eq!(program.code, vec![
+            OpCode::ENTER as i32, 0,        // 000
+            OpCode::PUSH as i32, 1,         // 002
+            OpCode::JMP0 as i32, 11,        // 004
+            OpCode::PUSH as i32, 1,         // 006
+            OpCode::PRINT as i32,           // 008
+            OpCode::JMP as i32, 14,         // 009
+            OpCode::PUSH as i32, 0,         // 011
+            OpCode::PRINT as i32,           // 013
+            OpCode::HALT as i32,            // 014
+        ]);
+    }
+
+    #[test]
+    fn codegen_globals_test() {
+        let stdout = run(r#"let x = 10
+        let y = x
+        let z = x + 5
+        if y >= x && z != 10 {
+            let hello = 100
+            print(hello)
+        }
+        print(z - y)"#);
+        assert_eq!(stdout, "100\n5\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_function_reads_global_test() {
+        let stdout = run(r#"let count = 1
+        fn show() {
+            print(count)
+        }
+        fn bump() {
+            count = count + 1
+        }
+        show()
+        bump()
+        show()"#);
+        assert_eq!(stdout, "1\n2\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_logical_operators_test() {
+        let stdout = run(r#"let yes = 1 == 1
+        print(1 < 2 && 2 < 1)
+        print(0 || 5)
+        print(!yes || 0)
+        print(-3 * 2)
+        print(nil && 1)
+        print("a" && "b")
+        print(nil || "default")"#);
+        assert_eq!(stdout, "false\n5\n0\n-6\nnil\nb\ndefault\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_truthiness_test() {
+        let stdout = run(r#"fn check(x) {
+            if x {
+                print("truthy")
+            } else {
+                print("falsy")
+            }
+        }
+        check(2)
+        check(-1)
+        check(0)
+        check(0.0)
+        check(0.5)
+        check("")
+        check(nil)
+        check(false)
+        print(!2)
+        print(!"")
+        print(2 || 3)
+        print(0 && 3)
+        print(0.0 || "zero")"#);
+        assert_eq!(
+            stdout,
+            "truthy\ntruthy\nfalsy\nfalsy\ntruthy\ntruthy\nfalsy\nfalsy\nfalse\nfalse\n2\n0\nzero\nBYE!\n",
+        );
+    }
+
+    #[test]
+    fn codegen_short_circuit_test() {
+        let stdout = run(r#"fn loud(x) {
+            print(x)
+            return x
+        }
+        let x = nil
+        if x != nil && len(x) > 0 {
+            print("not empty")
+        }
+        print(loud(false) && loud(1))
+        print(loud(2) || loud(3))
+        print(loud(0) || loud(4))"#);
+        assert_eq!(stdout, "false\nfalse\n2\n2\n0\n4\n4\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_literals_test() {
+        let stdout = run(r#"fn nothing() {
+        }
+        print(true)
+        print(!false)
+        print(nil)
+        print(nothing())"#);
+        assert_eq!(stdout, "true\ntrue\nnil\nnil\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_strings_test() {
+        let stdout = run(r#"let name = "tên"
+        let greeting = "xin chào " + name
+        print(greeting)
+        print(len(greeting))
+        print(greeting[4])
+        print(greeting[4..8] + "!")
+        print(name == "t" + "ên")
+        print("b" > "a")"#);
+        assert_eq!(stdout, "xin chào tên\n12\nc\nchào!\ntrue\ntrue\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_index_order_test() {
+        // The string runs before the index, the start before the end
+        let stdout = run(r#"fn f(x) {
+            print(x)
+            return x
+        }
+        print(f("abc")[f(1)])
+        print(f("abc")[f(0)..f(2)])"#);
+        assert_eq!(stdout, "abc\n1\nb\nabc\n0\n2\nab\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_interpolation_test() {
+        let stdout = run(r#"let name = "Gust"
+        let version = 1
+        print("Hello ${name}!")
+        print("${name} v${version + 0.5}, ${nil} ${version > 0}")
+        print("${""}")
+        print('say \"${"${name}"}\"')"#);
+        assert_eq!(stdout, "Hello Gust!\nGust v1.5, nil true\n\nsay \"Gust\"\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_interpolation_order_test() {
+        // The parts run from left to right
+        let stdout = run(r#"fn f(x) {
+            print(x)
+            return x
+        }
+        print("${f(3)}${f(4)}-${f(5)}")"#);
+        assert_eq!(stdout, "3\n4\n5\n34-5\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_comments_test() {
+        let stdout = run(r#"/// Doubles a number
+        fn double(n) {
+            return n * 2 // not / 2
+        }
+        /* print(1)
+           /* nested */
+           print(2) */ print(double(21))
+        print(3 /* inline */ + 4)"#);
+        assert_eq!(stdout, "42\n7\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_crlf_test() {
+        let stdout = run("fn twice(n) {\r\n\treturn n * 2\r\n}\r\n\r\nif twice(2) == 4 {\r\n\tprint('ok')\r\n}\r\n");
+        assert_eq!(stdout, "ok\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_operators_test() {
+        let stdout = run(r#"let x = 10
+        x += 5
+        x -= 1
+        x *= 3
+        x /= 2
+        print(x)
+        print(x % 4)
+        print(-7 % 3)
+        print(7.5 % 2)
+        print(2 ** 10)
+        print(-2 ** 2)
+        print(2 ** -1)
+        print((-1) ** 5000000000)
+        print(!(x > 100))
+        print(!true)
+        print(-(1.5))"#);
+        assert_eq!(stdout, "21\n1\n-1\n1.5\n1024\n-4\n0.5\n1\ntrue\nfalse\n-1.5\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_evaluation_order_test() {
+        // The left operand runs first, the right one is still the one
+        // subtracted
+        let stdout = run(r#"fn f(x) {
+            print(x)
+            return x
+        }
+        print(f(1) - f(2))
+        print(f(3) < f(4))"#);
+        assert_eq!(stdout, "1\n2\n-1\n3\n4\ntrue\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_bitwise_test() {
+        let stdout = run(r#"print(0xF0 & 0x3C)
+        print(0xF0 | 0x0F)
+        print(0xFF ^ 0x0F)
+        print(~5)
+        print(1 << 4)
+        print(1 << 63)
+        print(1 << 64)
+        print(-8 >> 1)
+        print(-8 >> 100)
+        print(8 >> 100)
+        print(0x12 & 0xF == 2)"#);
+        assert_eq!(
+            stdout,
+            "48\n255\n240\n-6\n16\n-9223372036854775808\n0\n-4\n-1\n0\ntrue\nBYE!\n",
+        );
+    }
+
+    #[test]
+    fn codegen_len_builtin_test() {
+        let actual = compile_source("print(len(1, 2))");
+        assert_eq!(actual, Err(CompileError::ArityMismatch {
+            name: "len".to_string(),
+            expected: 1,
+            found: 2,
+            span: Span { line: 1, column: 7, start: 6, len: 9 },
+        }));
+        // A function named `len` replaces the builtin
+        let stdout = run(r#"fn len(s) {
+            return 42
+        }
+        print(len("abc"))"#);
+        assert_eq!(stdout, "42\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_gc_test() {
+        let program = compile_source(r#"fn repeat(s, n) {
+            if n == 0 {
+                return s
+            }
+            let garbage = s + "?"
+            return repeat(s + "ab", n - 1)
+        }
+        let result = repeat("", 50)
+        print(len(result))
+        print(result[96..100])
+        gc()"#).unwrap();
+        let mut stdout = vec![];
+        let mut vm = VirtualMachine::new();
+        vm.set_gc_config(GcConfig { threshold: 0, growth_factor: 2 });
+        vm.load_verified(&program).unwrap();
+        vm.run(&mut stdout).unwrap();
+        assert_eq!(String::from_utf8(stdout).unwrap(), "100\nabab\nBYE!\n");
+        assert!(vm.gc_stats().collections > 1);
+        assert!(vm.gc_stats().bytes_freed > 0);
+        // The constants "", "?" and "ab", and the result
+        assert_eq!(vm.heap_objects(), 4);
+    }
+
+    #[test]
+    fn codegen_constant_pool_test() {
+        let program = compile_source(r#"print("hi")
+        print(2.5)
+        print("hi")
+        print(10000000000)"#).unwrap();
+        assert_eq!(program.constants, vec![
+            Constant::String("hi".into()),
+            Constant::Float(2.5),
+            Constant::Int(10_000_000_000),
+        ]);
+        assert_eq!(&program.code[..7], &[
+            OpCode::ENTER as i32, 0,
+            OpCode::CONST as i32, 0,
+            OpCode::PRINT as i32,
+            OpCode::CONST as i32, 1,
+        ]);
+        assert_eq!(run(r#"print("hi")
+        print(2.5 * 2)
+        print(10000000000 + 1)"#), "hi\n5.0\n10000000001\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_function_test() {
+        let stdout = run(r#"fn calc(a, b) {
+            let sum = a + b
+            return sum * 2
+        }
+        let v0 = 19
+        let v1 = 8
+        print(calc(v0, v1))"#);
+        assert_eq!(stdout, "54\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_recursive_function_test() {
+        let stdout = run(r#"print(fib(10))
+        fn fib(n) {
+            if n < 2 {
+                return n
+            }
+            return fib(n - 1) + fib(n - 2)
+        }"#);
+        assert_eq!(stdout, "55\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_undefined_variable_test() {
+        let actual = compile_source("print(x)");
+        assert_eq!(actual, Err(CompileError::UndefinedVariable {
+            name: "x".to_string(),
+            span: Span { line: 1, column: 7, start: 6, len: 1 },
+        }));
+    }
+
+    #[test]
+    fn codegen_while_loop_test() {
+        let stdout = run(r#"let i = 0
+        let total = 0
+        while i < 10 {
+            i += 1
+            if i % 2 == 0 {
+                continue
+            }
+            if i > 7 {
+                break
+            }
+            total += i
+        }
+        print(total)
+        print(i)"#);
+        assert_eq!(stdout, "16\n9\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_for_loop_test() {
+        let stdout = run(r#"for i in 0..3 {
+            print(i)
+        }
+        for c in "abc" {
+            print(c)
+        }
+        for i in 5..5 {
+            print("never")
+        }
+        for c in "" {
+            print("never")
+        }
+        let i = 10
+        for i in i..i + 2 {
+            print(i)
+        }
+        print(i)"#);
+        assert_eq!(stdout, "0\n1\n2\na\nb\nc\n10\n11\n10\nBYE!\n");
+        // The loop variable only lives in the loop
+        let actual = compile_source("for i in 0..3 {\n}\nprint(i)");
+        assert_eq!(actual, Err(CompileError::UndefinedVariable {
+            name: "i".to_string(),
+            span: Span { line: 3, column: 7, start: 24, len: 1 },
+        }));
+    }
+
+    #[test]
+    fn codegen_block_scope_test() {
+        let stdout = run(r#"let x = 1
+        if x == 1 {
+            let hello = 100
+            let x = x + 1
+            print(x)
+            if true {
+                let x = "inner"
+                print(x)
+                print(hello)
+            }
+            x = x * 10
+            print(x)
+        }
+        print(x)
+        fn f(a) {
+            let b = a + 1
+            if b > 0 {
+                let a = b * 2
+                return a
+            }
+            return b
+        }
+        print(f(1))
+        print(f(-5))"#);
+        assert_eq!(stdout, "2\ninner\n100\n20\n1\n4\n-4\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_slot_reuse_test() {
+        // The two blocks share slot 0, the top-level frame has one slot
+        let program = compile_source(r#"if true {
+            let a = 1
+        } else {
+            let b = 2
+        }"#).unwrap();
+        assert_eq!(program.code, vec![
+            OpCode::ENTER as i32, 1,        // 000
+            OpCode::TRUE as i32,            // 002
+            OpCode::JMP0 as i32, 11,        // 003
+            OpCode::PUSH as i32, 1,         // 005
+            OpCode::LSTORE as i32, 0,       // 007
+            OpCode::JMP as i32, 15,         // 009
+            OpCode::PUSH as i32, 2,         // 011
+            OpCode::LSTORE as i32, 0,       // 013
+            OpCode::POP as i32,             // 015
+            OpCode::HALT as i32,            // 016
+        ]);
+        let stdout = run(r#"fn f() {
+            if true {
+                let a = "a"
+                let b = "b"
+                print(a + b)
+            }
+            let c = "c"
+            if true {
+                let d = "d"
+                print(c + d)
+            }
+        }
+        f()"#);
+        assert_eq!(stdout, "ab\ncd\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_function_locals_test() {
+        let program = compile_source(r#"fn f(a) {
+            let b = a + 1
+            return b
+        }
+        print(f(1))"#).unwrap();
+        assert_eq!(program.code[..6].to_vec(), vec![
+            OpCode::ENTER as i32, 1,        // 000
+            OpCode::LLOAD as i32, -4,       // 002
+            OpCode::PUSH as i32, 1,         // 004
+        ]);
+        // The locals survive the recursive calls, and the values pushed by
+        // the expressions in between
+        let stdout = run(r#"fn fib(n) {
+            if n < 2 {
+                return n
+            }
+            let a = fib(n - 1)
+            let b = fib(n - 2)
+            let sum = a + b
+            return sum
+        }
+        fn count(s) {
+            let total = 0
+            for c in s {
+                let seen = total
+                total = seen + len(c)
+            }
+            return total
+        }
+        print(fib(15))
+        print(count("gust") + fib(3))"#);
+        assert_eq!(stdout, "610\n6\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_frame_overflow_test() {
+        // More locals than the stack has room for
+        let lets = (0..1100).map(|i| format!("let v{} = {}\n", i, i)).collect::<String>();
+        let source = format!("print(1)\nif true {{\n{}}}", lets);
+        let program = compile_source(&source).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_verified(&program).unwrap();
+        let err = vm.run(&mut vec![]).unwrap_err();
+        assert_eq!(err, VmError::StackOverflow { ip: program.entrypoint, opcode: OpCode::ENTER });
+        // The prologue belongs to the first statement
+        assert_eq!(program.span_at(err.ip()), Some(Span { line: 1, column: 1, start: 0, len: 8 }));
+        let last = program.code.len() - 1;
+        assert_eq!(program.span_at(last).map(|span| span.line), Some(2));
+    }
+
+    #[test]
+    fn codegen_used_before_declaration_test() {
+        let actual = compile_source("print(x)\nlet x = 1");
+        assert_eq!(actual, Err(CompileError::UsedBeforeDeclaration {
+            name: "x".to_string(),
+            span: Span { line: 1, column: 7, start: 6, len: 1 },
+        }));
+        let actual = compile_source("if true {\nlet y = y + 1\n}");
+        assert_eq!(actual, Err(CompileError::UsedBeforeDeclaration {
+            name: "y".to_string(),
+            span: Span { line: 2, column: 9, start: 18, len: 1 },
+        }));
+        let actual = compile_source("fn f() {\nz = 1\nlet z = 2\n}");
+        assert_eq!(actual, Err(CompileError::UsedBeforeDeclaration {
+            name: "z".to_string(),
+            span: Span { line: 2, column: 1, start: 9, len: 5 },
+        }));
+    }
+
+    #[test]
+    fn codegen_duplicate_variable_test() {
+        let actual = compile_source("let x = 1\nlet x = 2");
+        assert_eq!(actual, Err(CompileError::DuplicateVariable {
+            name: "x".to_string(),
+            span: Span { line: 2, column: 1, start: 10, len: 9 },
+        }));
+        let actual = compile_source("fn f(a) {\nlet a = 2\n}");
+        assert_eq!(actual, Err(CompileError::DuplicateVariable {
+            name: "a".to_string(),
+            span: Span { line: 2, column: 1, start: 10, len: 9 },
+        }));
+        assert!(compile_source("fn f(a, a) {\n}").is_err());
+        assert!(compile_source("if true {\nlet a = 1\nlet a = 2\n}").is_err());
+        // Shadowing in a nested block is fine
+        assert!(compile_source("let a = 1\nif true {\nlet a = 2\n}").is_ok());
+    }
+
+    #[test]
+    fn codegen_nested_loops_test() {
+        let stdout = run(r#"fn pairs(n) {
+            let found = ""
+            for i in 0..n {
+                for j in 0..n {
+                    if j == i {
+                        continue
+                    }
+                    if j > i {
+                        break
+                    }
+                    found = "${found}${i}${j} "
+                }
+                for c in "xy" {
+                    if i == 2 {
+                        break
+                    }
+                    found = found + c
+                }
+            }
+            return found
+        }
+        print(pairs(3))
+        let count = 0
+        for a in "ab" {
+            let i = 0
+            while true {
+                i += 1
+                for b in "abc" {
+                    if b == a {
+                        break
+                    }
+                    count += 1
+                }
+                if i == 2 {
+                    break
+                }
+            }
+        }
+        print(count)"#);
+        assert_eq!(stdout, "xy10 xy20 21 \n2\nBYE!\n");
+    }
+
+    #[test]
+    fn codegen_break_outside_loop_test() {
+        let actual = compile_source("let x = 1\nbreak");
+        assert_eq!(actual, Err(CompileError::BreakOutsideLoop {
+            keyword: "break",
+            span: Span { line: 2, column: 1, start: 10, len: 5 },
+        }));
+        let actual = compile_source(r#"fn f() {
+            continue
+        }"#);
+        assert_eq!(actual, Err(CompileError::BreakOutsideLoop {
+            keyword: "continue",
+            span: Span { line: 2, column: 13, start: 21, len: 8 },
+        }));
+        assert_eq!(actual.unwrap_err().to_string(), "2:13: `continue` outside of a loop");
+    }
+
+    #[test]
+    fn codegen_arity_mismatch_test() {
+        let actual = compile_source(r#"fn f(a) {
+            return a
+        }
+        f(1, 2)"#);
+        assert_eq!(actual, Err(CompileError::ArityMismatch {
+            name: "f".to_string(),
+            expected: 1,
+            found: 2,
+            span: Span { line: 4, column: 9, start: 49, len: 7 },
+        }));
+    }
+
+    #[test]
+    fn codegen_source_map_test() {
+        let program = compile_source("let a = 0\nprint(10 / a)").unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.load_program(program.code.clone(), program.entrypoint);
+        let error = vm.run(&mut vec![]).unwrap_err();
+        assert_eq!(program.span_at(error.ip()), Some(Span { line: 2, column: 7, start: 16, len: 6 }));
+    }
+}
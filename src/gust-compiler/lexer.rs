@@ -1,30 +1,217 @@
-use std::{
-    iter::{Enumerate, Peekable},
-    str::Chars,
-};
-use super::token::Token;
-use super::string::fetch_string_slice;
+use std::{borrow::Cow, fmt, iter::Peekable, str::CharIndices};
+use crate::diagnostics::Diagnostic;
+use super::token::{Span, SpannedToken, Token};
+
+// The Lexer keeps track of where it is in the source while scanning, so
+// every token comes out with its Span: the line and column (both start
+// from 1, the column counts characters) and the byte range of the token
+// in the source.
+//
+// Since the chars are iterated with their byte offsets, slicing the source
+// with these offsets always lands on char boundaries, even for multi-byte
+// characters.
+//
+// The escapes in the strings are replaced while scanning, and the strings
+// with a `${...}` inside are split around it: the lexer keeps a stack of the
+// interpolations it is in, and goes back to the string at the `}` that
+// closes the expression.
+//
+// The `//` and `/* */` comments are skipped, block comments can be nested,
+// and one that spans several lines counts as a new line, like the one at
+// the end of a `//` comment. The `///` doc comments are kept as tokens, so
+// the parser can attach them to the declaration that follows.
+//
+// Any Unicode whitespace separates the tokens, but only `\n` ends a line:
+// a `\r\n` is a single EOL token, so the sources written on Windows lex
+// the same way, and a lone `\r` is just whitespace.
 
 pub struct Lexer<'a> {
-    chars: Peekable<Enumerate<Chars<'a>>>,
+    chars: Peekable<CharIndices<'a>>,
     source: &'a str,
+    line: usize,
+    column: usize,
+    token_start: Span,
+    interpolations: Vec<Interpolation>,
+}
+
+// A `${` whose expression is being scanned
+struct Interpolation {
+    // The quote of the string to go back to
+    quote: char,
+    // The `{` opened in the expression and not closed yet
+    depth: usize,
+    // From the start of the string to the `${`
+    span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    UnterminatedString { span: Span },
+    UnexpectedChar { c: char, span: Span },
+    MalformedNumber { span: Span },
+    InvalidEscape { c: char, span: Span },
+    InvalidUnicodeEscape { span: Span },
+    NumberOverflow { span: Span },
+    UnterminatedComment { span: Span },
+}
+
+enum NumberError {
+    Malformed,
+    Overflow,
+}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnterminatedString { span }
+            | LexError::UnexpectedChar { span, .. }
+            | LexError::MalformedNumber { span }
+            | LexError::InvalidEscape { span, .. }
+            | LexError::InvalidUnicodeEscape { span }
+            | LexError::NumberOverflow { span }
+            | LexError::UnterminatedComment { span } => *span,
+        }
+    }
+
+    // The error message, without the position
+    pub fn message(&self) -> String {
+        match self {
+            LexError::UnterminatedString { .. } => "unterminated string".to_string(),
+            LexError::UnexpectedChar { c, .. } => format!("unexpected character `{}`", c),
+            LexError::MalformedNumber { .. } => "malformed number literal".to_string(),
+            LexError::InvalidEscape { c, .. } => format!("unknown escape sequence `\\{}`", c),
+            LexError::InvalidUnicodeEscape { .. } => "invalid unicode escape".to_string(),
+            LexError::NumberOverflow { .. } => "number literal is too large".to_string(),
+            LexError::UnterminatedComment { .. } => "unterminated block comment".to_string(),
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let diagnostic = match self {
+            LexError::UnterminatedString { .. } => Diagnostic::error("E0001", self.message())
+                .with_help("add the closing quote at the end of the string"),
+            LexError::UnexpectedChar { .. } => Diagnostic::error("E0002", self.message()),
+            LexError::MalformedNumber { .. } => Diagnostic::error("E0003", self.message())
+                .with_help("numbers look like `42`, `1_000`, `4.5`, `1e-9`, `0xFF`, `0b1010` or `0o17`"),
+            LexError::InvalidEscape { .. } => Diagnostic::error("E0004", self.message())
+                .with_help(r#"the escapes are \n, \t, \r, \0, \\, \", \', \$ and \u{...}"#),
+            LexError::InvalidUnicodeEscape { .. } => Diagnostic::error("E0005", self.message())
+                .with_help("write the code point in hex between braces, like `\\u{1F680}`"),
+            LexError::NumberOverflow { .. } => Diagnostic::error("E0006", self.message())
+                .with_help("integers are 64 bits, up to 9223372036854775807, and floats go up to about 1.8e308"),
+            LexError::UnterminatedComment { .. } => Diagnostic::error("E0007", self.message())
+                .with_help("close it with `*/`, block comments can be nested"),
+        };
+        diagnostic.with_span(self.span())
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        write!(f, "{}:{}: {}", span.line, span.column, self.message())
+    }
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
         Self {
-            chars: input.chars().enumerate().peekable(),
+            chars: input.char_indices().peekable(),
             source: input,
+            line: 1,
+            column: 1,
+            token_start: Span::default(),
+            interpolations: vec![],
         }
     }
-}
 
-impl<'a> Iterator for Lexer<'a> {
-    type Item = Token<'a>;
+    // Scan the whole source, collecting every error instead of stopping
+    // at the first one
+    pub fn tokenize(self) -> Result<Vec<SpannedToken<'a>>, Vec<LexError>> {
+        let mut tokens = vec![];
+        let mut errors = vec![];
+        for result in self {
+            match result {
+                Ok(token) => tokens.push(token),
+                Err(error) => errors.push(error),
+            }
+        }
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(errors)
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while let Some((_, ' ')) = self.chars.peek() {
-            self.chars.next();
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    // Consume the next char, moving the current line and column along
+    fn bump(&mut self) -> Option<(usize, char)> {
+        let next = self.chars.next();
+        if let Some((_, c)) = next {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        next
+    }
+
+    // The span from the start of the current token to the next char
+    fn span(&mut self) -> Span {
+        self.span_from(self.token_start)
+    }
+
+    fn span_from(&mut self, start: Span) -> Span {
+        Span {
+            len: self.offset() - start.start,
+            ..start
+        }
+    }
+
+    // The source from the next char
+    fn rest(&mut self) -> &'a str {
+        let offset = self.offset();
+        &self.source[offset..]
+    }
+
+    // An empty span at the next char
+    fn position(&mut self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            start: self.offset(),
+            len: 0,
+        }
+    }
+
+    // Byte offset of the next char
+    fn offset(&mut self) -> usize {
+        match self.chars.peek() {
+            Some((offset, _)) => *offset,
+            None => self.source.len(),
+        }
+    }
+
+    fn scan(&mut self) -> Option<Result<Token<'a>, LexError>> {
+        // Inside an interpolation, the braces are counted to find the `}`
+        // that goes back to the string
+        if let (Some((_, c)), Some(interpolation)) = (self.chars.peek(), self.interpolations.last_mut()) {
+            match c {
+                '{' => interpolation.depth += 1,
+                '}' if interpolation.depth > 0 => interpolation.depth -= 1,
+                '}' => {
+                    let quote = interpolation.quote;
+                    self.interpolations.pop();
+                    self.bump();
+                    return Some(self.string(quote));
+                }
+                _ => {}
+            }
         }
         // Process Single-char tokens
         if let Some((_, c)) = self.chars.peek() {
@@ -36,147 +223,427 @@ impl<'a> Iterator for Lexer<'a> {
                 '[' => Some(Token::LeftSquareBracket),
                 ']' => Some(Token::RightSquareBracket),
                 ',' => Some(Token::Comma),
-                '.' => Some(Token::Dot),
-                '-' => Some(Token::Minus),
-                '+' => Some(Token::Plus),
-                '/' => Some(Token::Slash),
-                '*' => Some(Token::Star),
                 ':' => Some(Token::Colon),
+                '%' => Some(Token::Percent),
+                '^' => Some(Token::Caret),
+                '~' => Some(Token::Tilde),
                 '\n' => Some(Token::EOL),
+                // Only before a `\n`, the lone ones are whitespace
+                '\r' => {
+                    self.bump();
+                    Some(Token::EOL)
+                }
                 _ => None,
             } {
-                self.chars.next();
-                return Some(token);
+                self.bump();
+                return Some(Ok(token));
             }
         }
         // Conditional tokens and others
-        if let Some((start, c)) = self.chars.next() {
+        if let Some((start, c)) = self.bump() {
             let (_, c_next) = self.chars.peek().unwrap_or(&(0, '\0'));
             if let Some(token) = match c {
                 '!' => {
                     if c_next == &'=' {
                         Some(Token::BangEqual)
-                    } else if c_next.is_alphabetic() {
-                        Some(Token::Bang)
                     } else {
-                        None
+                        return Some(Ok(Token::Bang));
+                    }
+                }
+                '-' => {
+                    if c_next == &'=' {
+                        Some(Token::MinusEqual)
+                    } else {
+                        return Some(Ok(Token::Minus));
+                    }
+                }
+                '+' => {
+                    if c_next == &'=' {
+                        Some(Token::PlusEqual)
+                    } else {
+                        return Some(Ok(Token::Plus));
+                    }
+                }
+                '/' => {
+                    if c_next == &'=' {
+                        Some(Token::SlashEqual)
+                    } else {
+                        return Some(Ok(Token::Slash));
+                    }
+                }
+                '*' => {
+                    if c_next == &'=' {
+                        Some(Token::StarEqual)
+                    } else if c_next == &'*' {
+                        Some(Token::StarStar)
+                    } else {
+                        return Some(Ok(Token::Star));
                     }
                 }
                 '=' => {
                     if c_next == &'=' {
                         Some(Token::EqualEqual)
                     } else {
-                        Some(Token::Equal)
+                        return Some(Ok(Token::Equal));
+                    }
+                }
+                '.' => {
+                    if c_next == &'.' {
+                        Some(Token::DotDot)
+                    } else {
+                        // A single dot is the whole token, so don't
+                        // consume the character after it
+                        return Some(Ok(Token::Dot));
                     }
                 }
                 '>' => {
                     if c_next == &'=' {
                         Some(Token::GreaterEqual)
+                    } else if c_next == &'>' {
+                        Some(Token::GreaterGreater)
                     } else {
-                        Some(Token::Greater)
+                        return Some(Ok(Token::Greater));
                     }
                 }
                 '<' => {
                     if c_next == &'=' {
                         Some(Token::LessEqual)
+                    } else if c_next == &'<' {
+                        Some(Token::LessLess)
                     } else {
-                        Some(Token::Less)
+                        return Some(Ok(Token::Less));
                     }
                 }
                 '&' => {
                     if c_next == &'&' {
                         Some(Token::And)
                     } else {
-                        None
+                        return Some(Ok(Token::Ampersand));
                     }
                 }
                 '|' => {
                     if c_next == &'|' {
                         Some(Token::Or)
                     } else {
-                        None
-                    }
-                },
-                quote @ ('"' | '\'') => {
-                    let mut end = start;
-                    while let Some((next_end, c_next)) = self.chars.next() {
-                        if c_next == quote {
-                            end = next_end;
-                            break;
-                        }
-                    }
-                    if end != start {
-                        if let Some(content) = fetch_string_slice(self.source, start, end) {
-                            return Some(Token::String(content));
-                        } else {
-                            return None;
-                        }
-                    } else {
-                        return Some(Token::Invalid);
+                        return Some(Ok(Token::Pipe));
                     }
                 },
+                quote @ ('"' | '\'') => return Some(self.string(quote)),
                 _ => {
                     if c.is_alphabetic() {
-                        let mut end = start;
-                        while let Some((next_end, c_next)) = self.chars.peek() {
+                        while let Some((_, c_next)) = self.chars.peek() {
                             if c_next.is_alphanumeric() || c_next == &'_' {
-                                end = *next_end;
-                                self.chars.next();
+                                self.bump();
                             } else {
                                 break;
                             }
                         }
-                        let word = fetch_string_slice(self.source, start, end).unwrap_or("");
+                        let word = &self.source[start..self.offset()];
                         match word {
-                            "if" => return Some(Token::If),
-                            "else" => return Some(Token::Else),
-                            "fn" => return Some(Token::Func),
-                            "for" => return Some(Token::For),
-                            "while" => return Some(Token::While),
-                            "let" => return Some(Token::Let),
-                            "return" => return Some(Token::Return),
-                            "nil" => return Some(Token::Nil),
-                            "true" => return Some(Token::True),
-                            "false" => return Some(Token::False),
-                            "print" => return Some(Token::Print),
-                            _ => return Some(Token::Identifier(word)),
+                            "if" => return Some(Ok(Token::If)),
+                            "else" => return Some(Ok(Token::Else)),
+                            "fn" => return Some(Ok(Token::Func)),
+                            "for" => return Some(Ok(Token::For)),
+                            "in" => return Some(Ok(Token::In)),
+                            "while" => return Some(Ok(Token::While)),
+                            "break" => return Some(Ok(Token::Break)),
+                            "continue" => return Some(Ok(Token::Continue)),
+                            "let" => return Some(Ok(Token::Let)),
+                            "return" => return Some(Ok(Token::Return)),
+                            "nil" => return Some(Ok(Token::Nil)),
+                            "true" => return Some(Ok(Token::True)),
+                            "false" => return Some(Ok(Token::False)),
+                            "print" => return Some(Ok(Token::Print)),
+                            _ => return Some(Ok(Token::Identifier(word))),
                         }
                     }
-                    if c.is_digit(10) {
-                        let mut end = start;
-                        while let Some((next_end, c_next)) = self.chars.peek() {
-                            if c_next.is_digit(10) || c_next == &'_' || c_next == &'.' {
-                                end = *next_end;
-                                self.chars.next();
-                            } else {
-                                break;
-                            }
-                        }
-                        return Some(Token::Number(&self.source[start..=end]));
+                    if c.is_ascii_digit() {
+                        return Some(self.number(start));
                     }
                     None
                 }
             } {
-                self.chars.next();
-                return Some(token);
+                self.bump();
+                return Some(Ok(token));
             }
+            return Some(Err(LexError::UnexpectedChar { c, span: self.span() }));
         }
         None
     }
+
+    // Skip a comment at the next char. A doc comment is returned as a
+    // token, and so is the new line in a block comment.
+    fn comment(&mut self) -> Option<Result<Option<Token<'a>>, LexError>> {
+        let rest = self.rest();
+        if rest.starts_with("//") {
+            let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+            // The `\r` of a `\r\n` is part of the EOL
+            let text = line.strip_suffix('\r').unwrap_or(line);
+            let len = text.len();
+            while self.offset() < self.token_start.start + len {
+                self.bump();
+            }
+            // `////` is a regular comment
+            return match text.strip_prefix("///") {
+                Some(doc) if !doc.starts_with('/') => {
+                    Some(Ok(Some(Token::DocComment(doc.strip_prefix(' ').unwrap_or(doc)))))
+                }
+                _ => Some(Ok(None)),
+            };
+        }
+        if !rest.starts_with("/*") {
+            return None;
+        }
+        let mut depth = 0;
+        let mut new_line = false;
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("/*") {
+                depth += 1;
+            } else if rest.starts_with("*/") {
+                depth -= 1;
+            } else {
+                match self.bump() {
+                    Some((_, c)) => new_line |= c == '\n',
+                    None => return Some(Err(LexError::UnterminatedComment { span: self.span() })),
+                }
+                continue;
+            }
+            self.bump();
+            self.bump();
+            if depth == 0 {
+                return Some(Ok(if new_line { Some(Token::EOL) } else { None }));
+            }
+        }
+    }
+
+    // Scan a number literal: `42`, `1_000`, `4.5`, `1e-9`, `0xFF`, `0b1010`
+    // or `0o17`. The letters, digits and dots stuck to it are part of it,
+    // so `3.4.5` or `12ab` are reported as a single malformed number.
+    fn number(&mut self, start: usize) -> Result<Token<'a>, LexError> {
+        let decimal = !matches!(self.source.get(start..start + 2), Some("0x" | "0b" | "0o"));
+        let mut prev = '0';
+        while let Some(&(_, c)) = self.chars.peek() {
+            let is_part = match c {
+                // Stop before a `..` so ranges like `0..10` are not
+                // scanned as a single number
+                '.' => {
+                    let mut lookahead = self.chars.clone();
+                    lookahead.next();
+                    !matches!(lookahead.peek(), Some((_, '.')))
+                },
+                '+' | '-' => decimal && matches!(prev, 'e' | 'E'),
+                c => c.is_alphanumeric() || c == '_',
+            };
+            if !is_part {
+                break;
+            }
+            prev = c;
+            self.bump();
+        }
+        parse_number(&self.source[start..self.offset()]).map_err(|err| match err {
+            NumberError::Malformed => LexError::MalformedNumber { span: self.span() },
+            NumberError::Overflow => LexError::NumberOverflow { span: self.span() },
+        })
+    }
+
+    // Scan a string up to its closing quote or to a `${`, from after the
+    // opening quote or the `}` of an interpolation. An invalid escape is
+    // reported once the whole string is scanned, so the lexer carries on
+    // after it.
+    fn string(&mut self, quote: char) -> Result<Token<'a>, LexError> {
+        let start = self.offset();
+        // Only allocated at the first escape
+        let mut value: Option<String> = None;
+        let mut error = None;
+        loop {
+            let position = self.position();
+            let (offset, c) = match self.bump() {
+                Some(next) => next,
+                None => return Err(LexError::UnterminatedString { span: self.span() }),
+            };
+            let interpolation = c == '$' && matches!(self.chars.peek(), Some((_, '{')));
+            if c == quote || interpolation {
+                let value = value.map_or(Cow::Borrowed(&self.source[start..offset]), Cow::Owned);
+                let token = if interpolation {
+                    self.bump();
+                    let span = self.span();
+                    self.interpolations.push(Interpolation { quote, depth: 0, span });
+                    Token::Interpolation(value)
+                } else {
+                    Token::String(value)
+                };
+                return error.map_or(Ok(token), Err);
+            }
+            if c == '\\' {
+                match self.escape(position) {
+                    Ok(c) => value.get_or_insert_with(|| self.source[start..offset].to_string()).push(c),
+                    Err(err @ LexError::UnterminatedString { .. }) => return Err(err),
+                    Err(err) => {
+                        error.get_or_insert(err);
+                    }
+                }
+            } else if let Some(value) = &mut value {
+                value.push(c);
+            }
+        }
+    }
+
+    // The char for the escape after a `\`
+    fn escape(&mut self, start: Span) -> Result<char, LexError> {
+        let c = match self.bump() {
+            Some((_, c)) => c,
+            None => return Err(LexError::UnterminatedString { span: self.span() }),
+        };
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            '\\' | '"' | '\'' | '$' => Ok(c),
+            'u' => self.unicode_escape(start),
+            c => Err(LexError::InvalidEscape { c, span: self.span_from(start) }),
+        }
+    }
+
+    // `\u{1F680}`: from 1 to 6 hex digits between braces, the code of any
+    // char but a surrogate
+    fn unicode_escape(&mut self, start: Span) -> Result<char, LexError> {
+        let mut digits = String::new();
+        let mut closed = false;
+        if let Some((_, '{')) = self.chars.peek() {
+            self.bump();
+            while let Some(&(_, c)) = self.chars.peek() {
+                if !c.is_ascii_hexdigit() {
+                    break;
+                }
+                digits.push(c);
+                self.bump();
+            }
+            if let Some((_, '}')) = self.chars.peek() {
+                self.bump();
+                closed = true;
+            }
+        }
+        Some(digits)
+            .filter(|digits| closed && (1..=6).contains(&digits.len()))
+            .and_then(|digits| u32::from_str_radix(&digits, 16).ok())
+            .and_then(char::from_u32)
+            .ok_or_else(|| LexError::InvalidUnicodeEscape { span: self.span_from(start) })
+    }
+}
+
+// The whitespace between the tokens: everything Unicode considers a
+// whitespace but `\n`, and the byte order mark some editors put at the
+// start of a file
+fn is_whitespace(c: char) -> bool {
+    (c.is_whitespace() && c != '\n') || c == '\u{FEFF}'
+}
+
+// A number without a `.` or an exponent is an integer, the others are
+// floats. A literal that doesn't fit in an i64 or an f64 is an error,
+// instead of being silently rounded to the infinity.
+fn parse_number(text: &str) -> Result<Token<'static>, NumberError> {
+    let radix = match text.get(..2) {
+        Some("0x") => 16,
+        Some("0b") => 2,
+        Some("0o") => 8,
+        _ => 10,
+    };
+    if radix != 10 {
+        let digits = digits(&text[2..], radix)?;
+        return i64::from_str_radix(&digits, radix).map(Token::Int).map_err(|_| NumberError::Overflow);
+    }
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(index) => (&text[..index], Some(&text[index + 1..])),
+        None => (text, None),
+    };
+    let (integer, fraction) = match mantissa.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (mantissa, None),
+    };
+    let mut number = digits(integer, 10)?;
+    if let Some(fraction) = fraction {
+        number.push('.');
+        number.push_str(&digits(fraction, 10)?);
+    }
+    if let Some(exponent) = exponent {
+        number.push('e');
+        let exponent = match exponent.strip_prefix('-') {
+            Some(exponent) => {
+                number.push('-');
+                exponent
+            }
+            None => exponent.strip_prefix('+').unwrap_or(exponent),
+        };
+        number.push_str(&digits(exponent, 10)?);
+    }
+    if fraction.is_none() && exponent.is_none() {
+        return number.parse().map(Token::Int).map_err(|_| NumberError::Overflow);
+    }
+    match number.parse::<f64>() {
+        Ok(n) if n.is_finite() => Ok(Token::Float(n)),
+        Ok(_) => Err(NumberError::Overflow),
+        Err(_) => Err(NumberError::Malformed),
+    }
+}
+
+// The digits of a number without their `_` separators, which can only be
+// put between two digits
+fn digits(digits: &str, radix: u32) -> Result<String, NumberError> {
+    let valid = !digits.is_empty()
+        && !digits.starts_with('_')
+        && !digits.ends_with('_')
+        && !digits.contains("__")
+        && digits.chars().all(|c| c == '_' || c.is_digit(radix));
+    if valid {
+        Ok(digits.replace('_', ""))
+    } else {
+        Err(NumberError::Malformed)
+    }
+}
+
+// The lexer doesn't stop at a bad character, it reports a LexError for
+// it and carries on with the rest of the source.
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<SpannedToken<'a>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while self.chars.peek().is_some_and(|&(_, c)| is_whitespace(c)) && !self.rest().starts_with("\r\n") {
+                self.bump();
+            }
+            self.token_start = self.position();
+            match self.comment() {
+                None => break,
+                Some(Ok(None)) => continue,
+                Some(Ok(Some(token))) => return Some(Ok(SpannedToken { token, span: self.span() })),
+                Some(Err(error)) => return Some(Err(error)),
+            }
+        }
+        if self.chars.peek().is_none() {
+            // The source ends in the middle of an interpolation
+            let interpolation = self.interpolations.pop()?;
+            return Some(Err(LexError::UnterminatedString { span: interpolation.span }));
+        }
+        let token = self.scan()?;
+        Some(token.map(|token| SpannedToken { token, span: self.span() }))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Lexer, Token};
+    use std::borrow::Cow;
+    use super::{LexError, Lexer, Span, Token};
     #[test]
     fn lexer_variable_declaration_test() {
         let lexer = Lexer::new("let x = 10");
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::Let,
             Token::Identifier("x"),
             Token::Equal,
-            Token::Number("10")
+            Token::Int(10)
         ])
     }
 
@@ -185,12 +652,12 @@ mod tests {
         let lexer = Lexer::new(r#"let x = 10
             let y = x
         "#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::Let,
             Token::Identifier("x"),
             Token::Equal,
-            Token::Number("10"),
+            Token::Int(10),
             Token::EOL,
             Token::Let,
             Token::Identifier("y"),
@@ -205,7 +672,7 @@ mod tests {
         let lexer = Lexer::new(r#"if a != b {
             print(a)
         }"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::If,
             Token::Identifier("a"),
@@ -229,7 +696,7 @@ mod tests {
         } else {
             print(b)
         }"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::If,
             Token::Identifier("a"),
@@ -260,7 +727,7 @@ mod tests {
         let lexer = Lexer::new(r#"if a != b && c == 10 || d == x {
             print(a)
         }"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::If,
             Token::Identifier("a"),
@@ -269,7 +736,7 @@ mod tests {
             Token::And,
             Token::Identifier("c"),
             Token::EqualEqual,
-            Token::Number("10"),
+            Token::Int(10),
             Token::Or,
             Token::Identifier("d"),
             Token::EqualEqual,
@@ -288,15 +755,15 @@ mod tests {
     #[test]
     fn lexer_mathematic_expression_test() {
         let lexer = Lexer::new(r#"5 + a * 10_000 / 4.5 - c"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
-            Token::Number("5"),
+            Token::Int(5),
             Token::Plus,
             Token::Identifier("a"),
             Token::Star,
-            Token::Number("10_000"),
+            Token::Int(10_000),
             Token::Slash,
-            Token::Number("4.5"),
+            Token::Float(4.5),
             Token::Minus,
             Token::Identifier("c")
         ])
@@ -305,28 +772,29 @@ mod tests {
     #[test]
     fn lexer_number_underdash_test() {
         let lexer = Lexer::new(r#"1_000_000"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
-            Token::Number("1_000_000"),
+            Token::Int(1_000_000),
         ])
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn lexer_decimal_number_test() {
         let lexer = Lexer::new(r#"3.14159265359"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
-            Token::Number("3.14159265359"),
+            Token::Float(3.14159265359),
         ])
     }
 
     #[test]
     fn lexer_negative_number_test() {
         let lexer = Lexer::new(r#"-2412"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::Minus,
-            Token::Number("2412"),
+            Token::Int(2412),
         ])
     }
 
@@ -335,7 +803,7 @@ mod tests {
         let lexer = Lexer::new(r#"fn sum(a, b) {
             return a + b
         }"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::Func,
             Token::Identifier("sum"),
@@ -358,65 +826,67 @@ mod tests {
     #[test]
     fn lexer_string_test() {
         let lexer = Lexer::new(r#""hello world""#);
-        let actual = lexer.collect::<Vec<Token>>();
-        assert!(actual == vec![Token::String(r#""hello world""#)])
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert!(actual == vec![Token::String("hello world".into())])
     }
 
     #[test]
     fn lexer_single_quoted_string_test() {
         let lexer = Lexer::new(r#"'hello world'"#);
-        let actual = lexer.collect::<Vec<Token>>();
-        assert!(actual == vec![Token::String("'hello world'")])
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert!(actual == vec![Token::String("hello world".into())])
     }
 
     #[test]
     fn lexer_invalid_string_test() {
         let lexer = Lexer::new(r#""hello world"#);
-        let actual = lexer.collect::<Vec<Token>>();
-        assert!(actual == vec![Token::Invalid])
+        let actual = lexer.collect::<Vec<_>>();
+        assert!(actual == vec![Err(LexError::UnterminatedString {
+            span: Span { line: 1, column: 1, start: 0, len: 12 }
+        })])
     }
 
     #[test]
     fn lexer_empty_string_test() {
         let lexer = Lexer::new(r#""""#);
-        let actual = lexer.collect::<Vec<Token>>();
-        assert!(actual == vec![Token::String(r#""""#)])
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert!(actual == vec![Token::String("".into())])
     }
 
     #[test]
     fn lexer_empty_single_quoted_string_test() {
         let lexer = Lexer::new(r#"''"#);
-        let actual = lexer.collect::<Vec<Token>>();
-        assert!(actual == vec![Token::String(r#"''"#)])
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert!(actual == vec![Token::String("".into())])
     }
 
     #[test]
     fn lexer_unicode_string_test() {
         let lexer = Lexer::new(r#"'Im a rocket 🚀'"#);
-        let actual = lexer.collect::<Vec<Token>>();
-        assert!(actual == vec![Token::String(r#"'Im a rocket 🚀'"#)])
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert!(actual == vec![Token::String("Im a rocket 🚀".into())])
     }
 
     #[test]
     fn lexer_string_variable_definition_test() {
         let lexer = Lexer::new(r#"let s = "Tiếng Việt""#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::Let,
             Token::Identifier("s"),
             Token::Equal,
-            Token::String(r#""Tiếng Việt""#)
+            Token::String("Tiếng Việt".into())
         ])
     }
 
     #[test]
     fn lexer_print_statement_with_string_test() {
         let lexer = Lexer::new(r#"print("Xin chào!!!")"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::Print,
             Token::LeftParen,
-            Token::String(r#""Xin chào!!!""#),
+            Token::String("Xin chào!!!".into()),
             Token::RightParen
         ])
     }
@@ -424,12 +894,376 @@ mod tests {
     #[test]
     fn lexer_unicode_identifier_test() {
         let lexer = Lexer::new(r#"let tên_tui = 'Huy'"#);
-        let actual = lexer.collect::<Vec<Token>>();
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
         assert!(actual == vec![
             Token::Let,
             Token::Identifier("tên_tui"),
             Token::Equal,
-            Token::String("'Huy'")
+            Token::String("Huy".into())
+        ])
+    }
+
+    #[test]
+    fn lexer_for_range_test() {
+        let lexer = Lexer::new(r#"for i in 0..10"#);
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert!(actual == vec![
+            Token::For,
+            Token::Identifier("i"),
+            Token::In,
+            Token::Int(0),
+            Token::DotDot,
+            Token::Int(10)
+        ])
+    }
+
+    #[test]
+    fn lexer_loop_keywords_test() {
+        let lexer = Lexer::new("while true { break continue breaks }");
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert_eq!(actual, vec![
+            Token::While,
+            Token::True,
+            Token::LeftBracket,
+            Token::Break,
+            Token::Continue,
+            Token::Identifier("breaks"),
+            Token::RightBracket,
+        ]);
+    }
+
+    #[test]
+    fn lexer_operators_without_spaces_test() {
+        let lexer = Lexer::new(r#"x=a>b<c"#);
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert!(actual == vec![
+            Token::Identifier("x"),
+            Token::Equal,
+            Token::Identifier("a"),
+            Token::Greater,
+            Token::Identifier("b"),
+            Token::Less,
+            Token::Identifier("c")
+        ])
+    }
+
+    #[test]
+    fn lexer_token_span_test() {
+        let lexer = Lexer::new("let s = 'chào'\n  print(s)");
+        let actual = lexer.map(|spanned| spanned.unwrap().span).collect::<Vec<Span>>();
+        assert_eq!(actual, vec![
+            Span { line: 1, column: 1, start: 0, len: 3 },
+            Span { line: 1, column: 5, start: 4, len: 1 },
+            Span { line: 1, column: 7, start: 6, len: 1 },
+            Span { line: 1, column: 9, start: 8, len: 7 },
+            Span { line: 1, column: 15, start: 15, len: 1 },
+            Span { line: 2, column: 3, start: 18, len: 5 },
+            Span { line: 2, column: 8, start: 23, len: 1 },
+            Span { line: 2, column: 9, start: 24, len: 1 },
+            Span { line: 2, column: 10, start: 25, len: 1 },
         ])
     }
+
+    #[test]
+    fn lexer_unicode_identifier_span_test() {
+        let lexer = Lexer::new("tên_tui + x");
+        let actual = lexer.map(|spanned| spanned.unwrap().span).collect::<Vec<Span>>();
+        assert_eq!(actual[0], Span { line: 1, column: 1, start: 0, len: 8 });
+        assert_eq!(actual[1], Span { line: 1, column: 9, start: 9, len: 1 });
+    }
+
+    #[test]
+    fn lexer_unexpected_char_test() {
+        let lexer = Lexer::new("a @ b # c ` d");
+        let actual = lexer.tokenize();
+        assert_eq!(actual, Err(vec![
+            LexError::UnexpectedChar { c: '@', span: Span { line: 1, column: 3, start: 2, len: 1 } },
+            LexError::UnexpectedChar { c: '#', span: Span { line: 1, column: 7, start: 6, len: 1 } },
+            LexError::UnexpectedChar { c: '`', span: Span { line: 1, column: 11, start: 10, len: 1 } },
+        ]))
+    }
+
+    #[test]
+    fn lexer_keeps_going_after_error_test() {
+        let lexer = Lexer::new("let x = 3.4.5\nprint(x @ 2)");
+        let actual = lexer.collect::<Vec<_>>();
+        assert_eq!(actual.len(), 11);
+        assert_eq!(actual[3], Err(LexError::MalformedNumber {
+            span: Span { line: 1, column: 9, start: 8, len: 5 }
+        }));
+        assert_eq!(actual[8], Err(LexError::UnexpectedChar {
+            c: '@',
+            span: Span { line: 2, column: 9, start: 22, len: 1 }
+        }));
+        assert_eq!(actual[10].as_ref().map(|spanned| &spanned.token), Ok(&Token::RightParen));
+    }
+
+    #[test]
+    fn lexer_escape_test() {
+        let lexer = Lexer::new(r#""say \"hi\"\n" 'it\'s' "\t\\\$\u{1F680}\u{e9}" "plain""#);
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert_eq!(actual, vec![
+            Token::String("say \"hi\"\n".into()),
+            Token::String("it's".into()),
+            Token::String("\t\\$🚀é".into()),
+            Token::String("plain".into()),
+        ]);
+        // The strings without escapes borrow the source
+        assert!(matches!(actual[3], Token::String(Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn lexer_invalid_escape_test() {
+        let lexer = Lexer::new(r#"print("a\qb", "\u{D800}", "\u{1F680", "\u12")"#);
+        let actual = lexer.filter_map(Result::err).collect::<Vec<LexError>>();
+        assert_eq!(actual, vec![
+            LexError::InvalidEscape { c: 'q', span: Span { line: 1, column: 9, start: 8, len: 2 } },
+            LexError::InvalidUnicodeEscape { span: Span { line: 1, column: 16, start: 15, len: 8 } },
+            LexError::InvalidUnicodeEscape { span: Span { line: 1, column: 28, start: 27, len: 8 } },
+            LexError::InvalidUnicodeEscape { span: Span { line: 1, column: 40, start: 39, len: 2 } },
+        ]);
+        // The lexer carries on after the string
+        let lexer = Lexer::new(r#""\q" + 1"#);
+        let actual = lexer.collect::<Vec<_>>();
+        assert_eq!(actual.len(), 3);
+        assert_eq!(actual[2].as_ref().map(|spanned| &spanned.token), Ok(&Token::Int(1)));
+    }
+
+    #[test]
+    fn lexer_interpolation_test() {
+        let lexer = Lexer::new(r#""Hello ${name}!" '${a + "${b}"}' "{${ {} }}""#);
+        let actual = lexer.map(|spanned| spanned.unwrap()).collect::<Vec<_>>();
+        let tokens = actual.iter().map(|spanned| spanned.token.clone()).collect::<Vec<Token>>();
+        assert_eq!(tokens, vec![
+            Token::Interpolation("Hello ".into()),
+            Token::Identifier("name"),
+            Token::String("!".into()),
+            Token::Interpolation("".into()),
+            Token::Identifier("a"),
+            Token::Plus,
+            Token::Interpolation("".into()),
+            Token::Identifier("b"),
+            Token::String("".into()),
+            Token::String("".into()),
+            Token::Interpolation("{".into()),
+            Token::LeftBracket,
+            Token::RightBracket,
+            Token::String("}".into()),
+        ]);
+        assert_eq!(actual[0].span, Span { line: 1, column: 1, start: 0, len: 9 });
+        assert_eq!(actual[2].span, Span { line: 1, column: 14, start: 13, len: 3 });
+    }
+
+    #[test]
+    fn lexer_unterminated_interpolation_test() {
+        let lexer = Lexer::new(r#"print("a ${b)"#);
+        let actual = lexer.collect::<Vec<_>>();
+        assert_eq!(actual.last(), Some(&Err(LexError::UnterminatedString {
+            span: Span { line: 1, column: 7, start: 6, len: 5 },
+        })));
+        let lexer = Lexer::new(r#""a ${b} c"#);
+        let actual = lexer.collect::<Vec<_>>();
+        assert_eq!(actual.last(), Some(&Err(LexError::UnterminatedString {
+            span: Span { line: 1, column: 7, start: 6, len: 3 },
+        })));
+    }
+
+    #[test]
+    fn lexer_number_forms_test() {
+        let lexer = Lexer::new("0xFF 0b1010 0o17 0xdead_BEEF 1e-9 2.5E+3 1_000.000_1 7e2 0..10 1.5..2");
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert_eq!(actual, vec![
+            Token::Int(255),
+            Token::Int(10),
+            Token::Int(15),
+            Token::Int(0xdead_beef),
+            Token::Float(1e-9),
+            Token::Float(2500.0),
+            Token::Float(1000.0001),
+            Token::Float(700.0),
+            Token::Int(0),
+            Token::DotDot,
+            Token::Int(10),
+            Token::Float(1.5),
+            Token::DotDot,
+            Token::Int(2),
+        ]);
+    }
+
+    #[test]
+    fn lexer_malformed_number_test() {
+        for source in ["1__0", "1_", "3.4.5", "1.", "1._5", "12ab", "1e", "1e+", "0x", "0b102", "0o8", "0x1.5", "0x_1", "4.e3"] {
+            let actual = Lexer::new(source).tokenize();
+            let span = Span { line: 1, column: 1, start: 0, len: source.len() };
+            assert_eq!(actual, Err(vec![LexError::MalformedNumber { span }]), "{}", source);
+        }
+    }
+
+    #[test]
+    fn lexer_number_overflow_test() {
+        let lexer = Lexer::new("9223372036854775807 9223372036854775808 0x1_0000_0000_0000_0000 1e309");
+        let actual = lexer.collect::<Vec<_>>();
+        assert_eq!(actual[0].as_ref().map(|spanned| &spanned.token), Ok(&Token::Int(i64::MAX)));
+        assert_eq!(actual[1], Err(LexError::NumberOverflow {
+            span: Span { line: 1, column: 21, start: 20, len: 19 },
+        }));
+        assert!(matches!(actual[2], Err(LexError::NumberOverflow { .. })));
+        assert!(matches!(actual[3], Err(LexError::NumberOverflow { .. })));
+    }
+
+    #[test]
+    fn lexer_comments_test() {
+        let lexer = Lexer::new("a // b / c\n/* d /* e */ f */ g / h /* i\n */ j //// k\n/**/l");
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert_eq!(actual, vec![
+            Token::Identifier("a"),
+            Token::EOL,
+            Token::Identifier("g"),
+            Token::Slash,
+            Token::Identifier("h"),
+            Token::EOL,
+            Token::Identifier("j"),
+            Token::EOL,
+            Token::Identifier("l"),
+        ]);
+    }
+
+    #[test]
+    fn lexer_doc_comment_test() {
+        let lexer = Lexer::new("/// Adds two numbers.\n///\n///Returns an int\nfn add");
+        let actual = lexer.map(|spanned| spanned.unwrap()).collect::<Vec<_>>();
+        let tokens = actual.iter().map(|spanned| spanned.token.clone()).collect::<Vec<Token>>();
+        assert_eq!(tokens, vec![
+            Token::DocComment("Adds two numbers."),
+            Token::EOL,
+            Token::DocComment(""),
+            Token::EOL,
+            Token::DocComment("Returns an int"),
+            Token::EOL,
+            Token::Func,
+            Token::Identifier("add"),
+        ]);
+        assert_eq!(actual[0].span, Span { line: 1, column: 1, start: 0, len: 21 });
+    }
+
+    #[test]
+    fn lexer_unterminated_comment_test() {
+        let lexer = Lexer::new("a /* b /* c */\nd");
+        let actual = lexer.collect::<Vec<_>>();
+        assert_eq!(actual.len(), 2);
+        assert_eq!(actual[1], Err(LexError::UnterminatedComment {
+            span: Span { line: 1, column: 3, start: 2, len: 14 },
+        }));
+    }
+
+    // Sources that only differ by their whitespace, and the tokens they all
+    // lex to
+    #[test]
+    fn lexer_whitespace_corpus_test() {
+        let corpus = [
+            "let x = 1\nprint(x)\n",
+            "let x = 1\r\nprint(x)\r\n",
+            "let\tx\t=\t1\nprint(x)\n",
+            "\tlet x = 1 \t\r\n\tprint( x )\r\n",
+            "\u{FEFF}let x = 1\nprint(x)\n",
+            "let\u{A0}x\u{2003}=\u{3000}1\nprint(x)\n",
+            "let x = 1\r\r\nprint(x)\r\n",
+            "let x = 1 // one\r\nprint(x) /* x */\r\n",
+            "let\rx = 1\nprint(x)\n",
+            "let x = 1\x0B\x0C\nprint(x)\n",
+        ];
+        let expected = vec![
+            Token::Let,
+            Token::Identifier("x"),
+            Token::Equal,
+            Token::Int(1),
+            Token::EOL,
+            Token::Print,
+            Token::LeftParen,
+            Token::Identifier("x"),
+            Token::RightParen,
+            Token::EOL,
+        ];
+        for source in corpus.iter() {
+            let actual = Lexer::new(source).tokenize().map(|tokens| {
+                tokens.into_iter().map(|spanned| spanned.token).collect::<Vec<Token>>()
+            });
+            assert_eq!(actual, Ok(expected.clone()), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn lexer_crlf_span_test() {
+        let lexer = Lexer::new("a\r\n\tb\r\n/// doc\r\nc");
+        let actual = lexer.map(|spanned| spanned.unwrap()).collect::<Vec<_>>();
+        let tokens = actual.iter().map(|spanned| spanned.token.clone()).collect::<Vec<Token>>();
+        assert_eq!(tokens, vec![
+            Token::Identifier("a"),
+            Token::EOL,
+            Token::Identifier("b"),
+            Token::EOL,
+            Token::DocComment("doc"),
+            Token::EOL,
+            Token::Identifier("c"),
+        ]);
+        assert_eq!(actual[1].span, Span { line: 1, column: 2, start: 1, len: 2 });
+        assert_eq!(actual[2].span, Span { line: 2, column: 2, start: 4, len: 1 });
+        assert_eq!(actual[6].span, Span { line: 4, column: 1, start: 16, len: 1 });
+    }
+
+    #[test]
+    fn lexer_operators_test() {
+        let lexer = Lexer::new("a % b ** c += d -= e *= f /= g * h / !(i) !j != k");
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert_eq!(actual, vec![
+            Token::Identifier("a"),
+            Token::Percent,
+            Token::Identifier("b"),
+            Token::StarStar,
+            Token::Identifier("c"),
+            Token::PlusEqual,
+            Token::Identifier("d"),
+            Token::MinusEqual,
+            Token::Identifier("e"),
+            Token::StarEqual,
+            Token::Identifier("f"),
+            Token::SlashEqual,
+            Token::Identifier("g"),
+            Token::Star,
+            Token::Identifier("h"),
+            Token::Slash,
+            Token::Bang,
+            Token::LeftParen,
+            Token::Identifier("i"),
+            Token::RightParen,
+            Token::Bang,
+            Token::Identifier("j"),
+            Token::BangEqual,
+            Token::Identifier("k"),
+        ]);
+        let lexer = Lexer::new("a & b && c | d || e ^ ~f << g >> h <= i >= j");
+        let actual = lexer.map(|spanned| spanned.unwrap().token).collect::<Vec<Token>>();
+        assert_eq!(actual, vec![
+            Token::Identifier("a"),
+            Token::Ampersand,
+            Token::Identifier("b"),
+            Token::And,
+            Token::Identifier("c"),
+            Token::Pipe,
+            Token::Identifier("d"),
+            Token::Or,
+            Token::Identifier("e"),
+            Token::Caret,
+            Token::Tilde,
+            Token::Identifier("f"),
+            Token::LessLess,
+            Token::Identifier("g"),
+            Token::GreaterGreater,
+            Token::Identifier("h"),
+            Token::LessEqual,
+            Token::Identifier("i"),
+            Token::GreaterEqual,
+            Token::Identifier("j"),
+        ]);
+    }
 }
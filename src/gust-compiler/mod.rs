@@ -1,3 +1,6 @@
+pub mod ast;
+pub mod codegen;
 pub mod lexer;
+pub mod parser;
+pub mod resolver;
 pub mod token;
-pub mod string;
@@ -0,0 +1,1076 @@
+use std::fmt;
+use crate::diagnostics::Diagnostic;
+use super::ast::{BinaryOp, Expr, ExprKind, Stmt, StmtKind, UnaryOp};
+use super::lexer::{LexError, Lexer};
+use super::token::{Span, SpannedToken, Token};
+
+// A recursive-descent parser that turns the Lexer's token stream into
+// a list of statements.
+//
+// Operator precedence, from the lowest to the highest:
+//
+//   ||
+//   &&
+//   == !=
+//   < <= > >=
+//   |
+//   ^
+//   &
+//   << >>
+//   ..
+//   + -
+//   * / %
+//   ! - ~ (unary)
+//   ** (right-associative, so `2 ** 3 ** 2` is `2 ** (3 ** 2)`, and
+//      `-2 ** 2` is `-(2 ** 2)`)
+//   calls, literals, variables, (grouping)
+//
+// Unlike C, the bitwise operators bind tighter than the comparisons, so
+// `a & 1 == 0` is `(a & 1) == 0`.
+//
+// Statements are separated by new lines, so the Parser treats `Token::EOL`
+// as a terminator instead of skipping it like whitespace.
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Lex(LexError),
+    UnexpectedToken {
+        expected: &'static str,
+        found: String,
+        span: Span,
+    },
+    UnexpectedEof {
+        expected: &'static str,
+        span: Span,
+    },
+    // A `${}` with nothing between the braces
+    EmptyInterpolation {
+        span: Span,
+    },
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::Lex(error) => error.span(),
+            ParseError::UnexpectedToken { span, .. }
+            | ParseError::UnexpectedEof { span, .. }
+            | ParseError::EmptyInterpolation { span } => *span,
+        }
+    }
+
+    // The error message, without the position
+    pub fn message(&self) -> String {
+        match self {
+            ParseError::Lex(error) => error.message(),
+            ParseError::UnexpectedToken { expected, found, .. } => {
+                format!("expected {}, found {}", expected, found)
+            }
+            ParseError::UnexpectedEof { expected, .. } => {
+                format!("expected {}, found end of file", expected)
+            }
+            ParseError::EmptyInterpolation { .. } => "empty interpolation".to_string(),
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let diagnostic = match self {
+            ParseError::Lex(error) => return error.to_diagnostic(),
+            ParseError::UnexpectedToken { expected: "end of line", .. } => {
+                Diagnostic::error("E0101", self.message())
+                    .with_help("put every statement on its own line")
+            }
+            ParseError::UnexpectedToken { .. } => Diagnostic::error("E0101", self.message()),
+            ParseError::UnexpectedEof { .. } => Diagnostic::error("E0102", self.message()),
+            ParseError::EmptyInterpolation { .. } => Diagnostic::error("E0103", self.message())
+                .with_help("put an expression between the braces, like `${name}`"),
+        };
+        diagnostic.with_span(self.span())
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        write!(f, "{}:{}: {}", span.line, span.column, self.message())
+    }
+}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    lookahead: Option<SpannedToken<'a>>,
+    // The lexer keeps going after an error, so the parser collects them
+    // and reports all of them at the end
+    lex_errors: Vec<LexError>,
+    // The doc comments read since the last statement started, and where
+    // the last statement ended
+    docs: Vec<(&'a str, Span)>,
+    stmt_end: usize,
+    // The span of the last consumed token, where the current node ends
+    prev_span: Span,
+    // Where the source ends, for the errors at the end of the file
+    eof: Span,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(lexer: Lexer<'a>) -> Self {
+        let source = lexer.source();
+        let line = source.matches('\n').count() + 1;
+        let column = source.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        Self {
+            lexer,
+            lookahead: None,
+            lex_errors: vec![],
+            docs: vec![],
+            stmt_end: 0,
+            prev_span: Span::default(),
+            eof: Span {
+                line,
+                column,
+                start: source.len(),
+                len: 0,
+            },
+        }
+    }
+
+    // Parse the whole source. If there are lexical errors, all of them are
+    // returned, since the syntax errors are most likely caused by them.
+    pub fn parse(&mut self) -> Result<Vec<Stmt<'a>>, Vec<ParseError>> {
+        let result = self.program();
+        while self.advance().is_some() {}
+        if !self.lex_errors.is_empty() {
+            return Err(self.lex_errors.drain(..).map(ParseError::Lex).collect());
+        }
+        result.map_err(|error| vec![error])
+    }
+
+    fn program(&mut self) -> ParseResult<Vec<Stmt<'a>>> {
+        let mut program = vec![];
+        loop {
+            self.skip_eols();
+            if self.peek().is_none() {
+                break;
+            }
+            program.push(self.statement()?);
+        }
+        Ok(program)
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt<'a>> {
+        let start = self.next_span();
+        let docs = self.take_docs();
+        let kind = match self.peek() {
+            Some(Token::Let) => self.let_statement()?,
+            Some(Token::If) => self.if_statement()?,
+            Some(Token::While) => self.while_statement()?,
+            Some(Token::For) => self.for_statement()?,
+            Some(Token::Break) => {
+                self.advance();
+                StmtKind::Break
+            }
+            Some(Token::Continue) => {
+                self.advance();
+                StmtKind::Continue
+            }
+            Some(Token::Func) => self.func_declaration()?,
+            Some(Token::Return) => self.return_statement()?,
+            Some(Token::Print) => self.print_statement()?,
+            _ => self.expression_statement()?,
+        };
+        let mut stmt = Stmt::new(kind, start.to(self.prev_span));
+        self.stmt_end = stmt.span.start + stmt.span.len;
+        // Only the declarations keep their doc comments
+        if let StmtKind::Let { .. } | StmtKind::Func { .. } = stmt.kind {
+            stmt.docs = docs;
+        }
+        self.end_of_statement()?;
+        Ok(stmt)
+    }
+
+    // The doc comments since the end of the previous statement, the ones
+    // read before were inside of it
+    fn take_docs(&mut self) -> Vec<&'a str> {
+        let end = self.stmt_end;
+        self.docs
+            .drain(..)
+            .filter(|(_, span)| span.start >= end)
+            .map(|(doc, _)| doc)
+            .collect()
+    }
+
+    // A statement ends at a new line, at the closing bracket of the
+    // enclosing block or at the end of the file.
+    fn end_of_statement(&mut self) -> ParseResult<()> {
+        match self.peek() {
+            None | Some(Token::RightBracket) => Ok(()),
+            Some(Token::EOL) => {
+                self.advance();
+                Ok(())
+            }
+            Some(_) => Err(self.unexpected("end of line")),
+        }
+    }
+
+    fn let_statement(&mut self) -> ParseResult<StmtKind<'a>> {
+        self.expect(Token::Let, "`let`")?;
+        let name = self.identifier()?;
+        self.expect(Token::Equal, "`=`")?;
+        let value = self.expression()?;
+        Ok(StmtKind::Let { name, value })
+    }
+
+    fn if_statement(&mut self) -> ParseResult<StmtKind<'a>> {
+        self.expect(Token::If, "`if`")?;
+        let cond = self.expression()?;
+        let then_branch = self.block()?;
+        let else_branch = if self.eat(&Token::Else) {
+            if let Some(Token::If) = self.peek() {
+                let start = self.next_span();
+                let kind = self.if_statement()?;
+                Some(vec![Stmt::new(kind, start.to(self.prev_span))])
+            } else {
+                Some(self.block()?)
+            }
+        } else {
+            None
+        };
+        Ok(StmtKind::If { cond, then_branch, else_branch })
+    }
+
+    fn while_statement(&mut self) -> ParseResult<StmtKind<'a>> {
+        self.expect(Token::While, "`while`")?;
+        let cond = self.expression()?;
+        let body = self.block()?;
+        Ok(StmtKind::While { cond, body })
+    }
+
+    fn for_statement(&mut self) -> ParseResult<StmtKind<'a>> {
+        self.expect(Token::For, "`for`")?;
+        let var = self.identifier()?;
+        self.expect(Token::In, "`in`")?;
+        let iterable = self.expression()?;
+        let body = self.block()?;
+        Ok(StmtKind::For { var, iterable, body })
+    }
+
+    fn func_declaration(&mut self) -> ParseResult<StmtKind<'a>> {
+        self.expect(Token::Func, "`fn`")?;
+        let name = self.identifier()?;
+        self.expect(Token::LeftParen, "`(`")?;
+        let mut params = vec![];
+        if !self.eat(&Token::RightParen) {
+            loop {
+                params.push(self.identifier()?);
+                if !self.eat(&Token::Comma) {
+                    break;
+                }
+            }
+            self.expect(Token::RightParen, "`)`")?;
+        }
+        let body = self.block()?;
+        Ok(StmtKind::Func { name, params, body })
+    }
+
+    fn return_statement(&mut self) -> ParseResult<StmtKind<'a>> {
+        self.expect(Token::Return, "`return`")?;
+        match self.peek() {
+            None | Some(Token::EOL) | Some(Token::RightBracket) => Ok(StmtKind::Return(None)),
+            _ => Ok(StmtKind::Return(Some(self.expression()?))),
+        }
+    }
+
+    fn print_statement(&mut self) -> ParseResult<StmtKind<'a>> {
+        self.expect(Token::Print, "`print`")?;
+        self.expect(Token::LeftParen, "`(`")?;
+        let value = self.expression()?;
+        self.expect(Token::RightParen, "`)`")?;
+        Ok(StmtKind::Print(value))
+    }
+
+    fn expression_statement(&mut self) -> ParseResult<StmtKind<'a>> {
+        let expr = self.expression()?;
+        let op = match self.peek() {
+            Some(Token::Equal) => None,
+            Some(Token::PlusEqual) => Some(BinaryOp::Add),
+            Some(Token::MinusEqual) => Some(BinaryOp::Sub),
+            Some(Token::StarEqual) => Some(BinaryOp::Mul),
+            Some(Token::SlashEqual) => Some(BinaryOp::Div),
+            _ => return Ok(StmtKind::Expr(expr)),
+        };
+        if let ExprKind::Variable(name) = expr.kind {
+            self.advance();
+            let mut value = self.expression()?;
+            // `x += 1` is `x = x + 1`
+            if let Some(op) = op {
+                value = binary(op, expr, value);
+            }
+            return Ok(StmtKind::Assign { name, value });
+        }
+        Err(self.unexpected("end of line"))
+    }
+
+    fn block(&mut self) -> ParseResult<Vec<Stmt<'a>>> {
+        self.expect(Token::LeftBracket, "`{`")?;
+        let mut stmts = vec![];
+        loop {
+            self.skip_eols();
+            match self.peek() {
+                Some(Token::RightBracket) => {
+                    self.advance();
+                    break;
+                }
+                None => return Err(self.unexpected("`}`")),
+                _ => stmts.push(self.statement()?),
+            }
+        }
+        Ok(stmts)
+    }
+
+    pub fn expression(&mut self) -> ParseResult<Expr<'a>> {
+        self.or()
+    }
+
+    fn or(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            let right = self.and()?;
+            expr = binary(BinaryOp::Or, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.equality()?;
+        while self.eat(&Token::And) {
+            let right = self.equality()?;
+            expr = binary(BinaryOp::And, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.comparison()?;
+        while let Some(op) = match self.peek() {
+            Some(Token::EqualEqual) => Some(BinaryOp::Eq),
+            Some(Token::BangEqual) => Some(BinaryOp::Ne),
+            _ => None,
+        } {
+            self.advance();
+            let right = self.comparison()?;
+            expr = binary(op, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.bit_or()?;
+        while let Some(op) = match self.peek() {
+            Some(Token::Greater) => Some(BinaryOp::Gt),
+            Some(Token::GreaterEqual) => Some(BinaryOp::Ge),
+            Some(Token::Less) => Some(BinaryOp::Lt),
+            Some(Token::LessEqual) => Some(BinaryOp::Le),
+            _ => None,
+        } {
+            self.advance();
+            let right = self.bit_or()?;
+            expr = binary(op, expr, right);
+        }
+        Ok(expr)
+    }
+
+    // The bitwise operators bind tighter than the comparisons, so
+    // `flags & MASK == 0` is `(flags & MASK) == 0`
+    fn bit_or(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.bit_xor()?;
+        while self.eat(&Token::Pipe) {
+            let right = self.bit_xor()?;
+            expr = binary(BinaryOp::BitOr, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn bit_xor(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.bit_and()?;
+        while self.eat(&Token::Caret) {
+            let right = self.bit_and()?;
+            expr = binary(BinaryOp::BitXor, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn bit_and(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.shift()?;
+        while self.eat(&Token::Ampersand) {
+            let right = self.shift()?;
+            expr = binary(BinaryOp::BitAnd, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn shift(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.range()?;
+        while let Some(op) = match self.peek() {
+            Some(Token::LessLess) => Some(BinaryOp::Shl),
+            Some(Token::GreaterGreater) => Some(BinaryOp::Shr),
+            _ => None,
+        } {
+            self.advance();
+            let right = self.range()?;
+            expr = binary(op, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn range(&mut self) -> ParseResult<Expr<'a>> {
+        let start = self.term()?;
+        if self.eat(&Token::DotDot) {
+            let end = self.term()?;
+            let span = start.span.to(end.span);
+            return Ok(Expr::new(ExprKind::Range {
+                start: Box::new(start),
+                end: Box::new(end),
+            }, span));
+        }
+        Ok(start)
+    }
+
+    fn term(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.factor()?;
+        while let Some(op) = match self.peek() {
+            Some(Token::Plus) => Some(BinaryOp::Add),
+            Some(Token::Minus) => Some(BinaryOp::Sub),
+            _ => None,
+        } {
+            self.advance();
+            let right = self.factor()?;
+            expr = binary(op, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn factor(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.unary()?;
+        while let Some(op) = match self.peek() {
+            Some(Token::Star) => Some(BinaryOp::Mul),
+            Some(Token::Slash) => Some(BinaryOp::Div),
+            Some(Token::Percent) => Some(BinaryOp::Mod),
+            _ => None,
+        } {
+            self.advance();
+            let right = self.unary()?;
+            expr = binary(op, expr, right);
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr<'a>> {
+        let op = match self.peek() {
+            Some(Token::Minus) => UnaryOp::Neg,
+            Some(Token::Bang) => UnaryOp::Not,
+            Some(Token::Tilde) => UnaryOp::BitNot,
+            _ => return self.power(),
+        };
+        let start = self.next_span();
+        self.advance();
+        let expr = self.unary()?;
+        let span = start.to(expr.span);
+        Ok(Expr::new(ExprKind::Unary {
+            op,
+            expr: Box::new(expr),
+        }, span))
+    }
+
+    // `**` binds tighter than the unary operators on its left, and is
+    // right associative: -2 ** 3 ** 2 is -(2 ** (3 ** 2))
+    fn power(&mut self) -> ParseResult<Expr<'a>> {
+        let base = self.call()?;
+        if self.eat(&Token::StarStar) {
+            let exponent = self.unary()?;
+            return Ok(binary(BinaryOp::Pow, base, exponent));
+        }
+        Ok(base)
+    }
+
+    fn call(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.primary()?;
+        if let ExprKind::Variable(callee) = expr.kind {
+            if self.eat(&Token::LeftParen) {
+                let args = self.arguments()?;
+                expr = Expr::new(ExprKind::Call { callee, args }, expr.span.to(self.prev_span));
+            }
+        }
+        // s[1], s[1..3], s[0][0]
+        while self.eat(&Token::LeftSquareBracket) {
+            let index = self.expression()?;
+            self.expect(Token::RightSquareBracket, "`]`")?;
+            let span = expr.span.to(self.prev_span);
+            expr = Expr::new(ExprKind::Index { target: Box::new(expr), index: Box::new(index) }, span);
+        }
+        Ok(expr)
+    }
+
+    // Parse the argument list of a call, the opening paren is already
+    // consumed. New lines are allowed between the arguments.
+    fn arguments(&mut self) -> ParseResult<Vec<Expr<'a>>> {
+        let mut args = vec![];
+        self.skip_eols();
+        if self.eat(&Token::RightParen) {
+            return Ok(args);
+        }
+        loop {
+            self.skip_eols();
+            args.push(self.expression()?);
+            self.skip_eols();
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+        self.expect(Token::RightParen, "`)`")?;
+        Ok(args)
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr<'a>> {
+        let (token, span) = match self.peek_spanned() {
+            Some(SpannedToken { token, span }) => (token.clone(), *span),
+            None => return Err(self.unexpected("expression")),
+        };
+        let kind = match token {
+            Token::Int(n) => ExprKind::Int(n),
+            Token::Float(n) => ExprKind::Float(n),
+            Token::String(string) => ExprKind::String(string),
+            Token::Interpolation(_) => return self.interpolation(),
+            Token::True => ExprKind::Bool(true),
+            Token::False => ExprKind::Bool(false),
+            Token::Nil => ExprKind::Nil,
+            Token::Identifier(name) => ExprKind::Variable(name),
+            Token::LeftParen => {
+                self.advance();
+                let mut expr = self.expression()?;
+                self.expect(Token::RightParen, "`)`")?;
+                expr.span = span.to(self.prev_span);
+                return Ok(expr);
+            }
+            _ => return Err(self.unexpected("expression")),
+        };
+        self.advance();
+        Ok(Expr::new(kind, span))
+    }
+
+    // "Hello ${name}!" comes from the lexer as Interpolation("Hello "), the
+    // tokens of `name`, then String("!")
+    fn interpolation(&mut self) -> ParseResult<Expr<'a>> {
+        let start = self.next_span();
+        let mut parts = vec![];
+        loop {
+            let (token, span) = match self.peek_spanned() {
+                Some(SpannedToken { token, span }) => (token.clone(), *span),
+                None => return Err(self.unexpected("`}`")),
+            };
+            match token {
+                Token::Interpolation(part) => {
+                    self.advance();
+                    parts.push(Expr::new(ExprKind::String(part), span));
+                    self.check_not_empty(span)?;
+                    parts.push(self.expression()?);
+                }
+                Token::String(part) => {
+                    self.advance();
+                    parts.push(Expr::new(ExprKind::String(part), span));
+                    break;
+                }
+                _ => return Err(self.unexpected("`}`")),
+            }
+        }
+        Ok(Expr::new(ExprKind::Interpolation(parts), start.to(self.prev_span)))
+    }
+
+    // The rest of the string right after the `${` means there is no
+    // expression, the next token would be taken for it otherwise
+    fn check_not_empty(&mut self, interpolation: Span) -> ParseResult<()> {
+        let source = self.lexer.source();
+        let close = match self.peek_spanned() {
+            Some(SpannedToken { token: Token::String(_) | Token::Interpolation(_), span })
+                if source[span.start..].starts_with('}') => *span,
+            _ => return Ok(()),
+        };
+        // From the `${` to the `}`, when they are on the same line
+        let dollar = interpolation.start + interpolation.len - 2;
+        let between = &source[dollar..close.start];
+        let span = if between.contains('\n') {
+            Span { len: 1, ..close }
+        } else {
+            Span {
+                line: close.line,
+                column: close.column - between.chars().count(),
+                start: dollar,
+                len: close.start + 1 - dollar,
+            }
+        };
+        Err(ParseError::EmptyInterpolation { span })
+    }
+
+    fn identifier(&mut self) -> ParseResult<&'a str> {
+        match self.peek() {
+            Some(Token::Identifier(name)) => {
+                let name = *name;
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.unexpected("identifier")),
+        }
+    }
+
+    fn expect(&mut self, expected: Token<'a>, description: &'static str) -> ParseResult<()> {
+        if self.eat(&expected) {
+            Ok(())
+        } else {
+            Err(self.unexpected(description))
+        }
+    }
+
+    // The error for when the next token is not what the parser expected
+    fn unexpected(&mut self, expected: &'static str) -> ParseError {
+        let eof = self.eof;
+        let source = self.lexer.source();
+        match self.peek_spanned() {
+            // The token as it was written, not the name of its variant
+            Some(SpannedToken { token: Token::EOL, span }) => ParseError::UnexpectedToken {
+                expected,
+                found: "end of line".to_string(),
+                span: *span,
+            },
+            Some(SpannedToken { token, span }) => ParseError::UnexpectedToken {
+                expected,
+                found: source
+                    .get(span.start..span.start + span.len)
+                    .map_or_else(|| format!("{:?}", token), |text| format!("`{}`", text)),
+                span: *span,
+            },
+            None => ParseError::UnexpectedEof { expected, span: eof },
+        }
+    }
+
+    fn peek_spanned(&mut self) -> Option<&SpannedToken<'a>> {
+        if self.lookahead.is_none() {
+            for result in self.lexer.by_ref() {
+                match result {
+                    Ok(SpannedToken { token: Token::DocComment(doc), span }) => self.docs.push((doc, span)),
+                    Ok(token) => {
+                        self.lookahead = Some(token);
+                        break;
+                    }
+                    Err(error) => self.lex_errors.push(error),
+                }
+            }
+        }
+        self.lookahead.as_ref()
+    }
+
+    fn peek(&mut self) -> Option<&Token<'a>> {
+        self.peek_spanned().map(|spanned| &spanned.token)
+    }
+
+    fn advance(&mut self) -> Option<SpannedToken<'a>> {
+        self.peek_spanned();
+        let token = self.lookahead.take();
+        if let Some(SpannedToken { span, .. }) = token {
+            self.prev_span = span;
+        }
+        token
+    }
+
+    // The span of the next token, or the end of the file
+    fn next_span(&mut self) -> Span {
+        let eof = self.eof;
+        self.peek_spanned().map(|spanned| spanned.span).unwrap_or(eof)
+    }
+
+    // Consume the next token if it's the expected one
+    fn eat(&mut self, expected: &Token<'a>) -> bool {
+        if self.peek() == Some(expected) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_eols(&mut self) {
+        while self.eat(&Token::EOL) {}
+    }
+}
+
+fn binary<'a>(op: BinaryOp, left: Expr<'a>, right: Expr<'a>) -> Expr<'a> {
+    let span = left.span.to(right.span);
+    Expr::new(ExprKind::Binary {
+        op,
+        left: Box::new(left),
+        right: Box::new(right),
+    }, span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ParseError, Parser};
+    use crate::compiler::ast::{BinaryOp, Expr, ExprKind, Stmt, StmtKind, UnaryOp};
+    use crate::compiler::lexer::{LexError, Lexer};
+    use crate::compiler::token::Span;
+
+    fn parse(source: &str) -> Result<Vec<Stmt<'_>>, ParseError> {
+        Parser::new(Lexer::new(source)).parse().map_err(|mut errors| errors.remove(0))
+    }
+
+    // Spans are not compared, so the expected trees are built without them
+    fn stmt(kind: StmtKind<'_>) -> Stmt<'_> {
+        Stmt::new(kind, Span::default())
+    }
+
+    fn expr(kind: ExprKind<'_>) -> Expr<'_> {
+        Expr::new(kind, Span::default())
+    }
+
+    fn int<'a>(n: i64) -> Expr<'a> {
+        expr(ExprKind::Int(n))
+    }
+
+    fn var(name: &str) -> Expr<'_> {
+        expr(ExprKind::Variable(name))
+    }
+
+    fn unary(op: UnaryOp, operand: Expr<'_>) -> Expr<'_> {
+        expr(ExprKind::Unary { op, expr: Box::new(operand) })
+    }
+
+    fn binary<'a>(op: BinaryOp, left: Expr<'a>, right: Expr<'a>) -> Expr<'a> {
+        super::binary(op, left, right)
+    }
+
+    #[test]
+    fn parser_let_statement_test() {
+        let actual = parse("let x = 10").unwrap();
+        assert_eq!(actual, vec![stmt(StmtKind::Let {
+            name: "x",
+            value: int(10),
+        })]);
+    }
+
+    #[test]
+    fn parser_operator_precedence_test() {
+        let actual = parse("print(1 + 2 * 3 - 4)").unwrap();
+        assert_eq!(actual, vec![stmt(StmtKind::Print(binary(
+            BinaryOp::Sub,
+            binary(
+                BinaryOp::Add,
+                int(1),
+                binary(BinaryOp::Mul, int(2), int(3)),
+            ),
+            int(4),
+        )))]);
+    }
+
+    #[test]
+    fn parser_logical_precedence_test() {
+        let actual = parse("a || b && c >= 1 == !d").unwrap();
+        assert_eq!(actual, vec![stmt(StmtKind::Expr(binary(
+            BinaryOp::Or,
+            var("a"),
+            binary(
+                BinaryOp::And,
+                var("b"),
+                binary(
+                    BinaryOp::Eq,
+                    binary(BinaryOp::Ge, var("c"), int(1)),
+                    unary(UnaryOp::Not, var("d")),
+                ),
+            ),
+        )))]);
+    }
+
+    #[test]
+    fn parser_grouping_and_unary_test() {
+        let actual = parse("-(1 + 2.5)").unwrap();
+        assert_eq!(actual, vec![stmt(StmtKind::Expr(unary(
+            UnaryOp::Neg,
+            binary(BinaryOp::Add, int(1), expr(ExprKind::Float(2.5))),
+        )))]);
+    }
+
+    #[test]
+    fn parser_if_else_test() {
+        let actual = parse(r#"if y >= x && z != 10 {
+            let hello = 100
+        } else if z == 1 {
+            print("one")
+        } else {
+            x = 1
+        }"#).unwrap();
+        assert_eq!(actual, vec![stmt(StmtKind::If {
+            cond: binary(
+                BinaryOp::And,
+                binary(BinaryOp::Ge, var("y"), var("x")),
+                binary(BinaryOp::Ne, var("z"), int(10)),
+            ),
+            then_branch: vec![stmt(StmtKind::Let { name: "hello", value: int(100) })],
+            else_branch: Some(vec![stmt(StmtKind::If {
+                cond: binary(BinaryOp::Eq, var("z"), int(1)),
+                then_branch: vec![stmt(StmtKind::Print(expr(ExprKind::String("one".into()))))],
+                else_branch: Some(vec![stmt(StmtKind::Assign { name: "x", value: int(1) })]),
+            })]),
+        })]);
+    }
+
+    #[test]
+    fn parser_function_test() {
+        let actual = parse(r#"fn sum(a, b) {
+            return a + b
+        }
+        print(sum(1, 2))"#).unwrap();
+        assert_eq!(actual, vec![
+            stmt(StmtKind::Func {
+                name: "sum",
+                params: vec!["a", "b"],
+                body: vec![stmt(StmtKind::Return(Some(binary(
+                    BinaryOp::Add,
+                    var("a"),
+                    var("b"),
+                ))))],
+            }),
+            stmt(StmtKind::Print(expr(ExprKind::Call {
+                callee: "sum",
+                args: vec![int(1), int(2)],
+            }))),
+        ]);
+    }
+
+    #[test]
+    fn parser_index_test() {
+        let index = |target, index| expr(ExprKind::Index { target: Box::new(target), index: Box::new(index) });
+        let actual = parse("s[1]\nname(1)[0..2][i]").unwrap();
+        assert_eq!(actual, vec![
+            stmt(StmtKind::Expr(index(var("s"), int(1)))),
+            stmt(StmtKind::Expr(index(
+                index(
+                    expr(ExprKind::Call { callee: "name", args: vec![int(1)] }),
+                    expr(ExprKind::Range { start: Box::new(int(0)), end: Box::new(int(2)) }),
+                ),
+                var("i"),
+            ))),
+        ]);
+        assert!(parse("s[1").is_err());
+    }
+
+    #[test]
+    fn parser_interpolation_test() {
+        let string = |s: &'static str| expr(ExprKind::String(s.into()));
+        let actual = parse(r#"print("Hi ${name}, ${1 + 2}\t")"#).unwrap();
+        assert_eq!(actual, vec![stmt(StmtKind::Print(expr(ExprKind::Interpolation(vec![
+            string("Hi "),
+            var("name"),
+            string(", "),
+            binary(BinaryOp::Add, int(1), int(2)),
+            string("\t"),
+        ]))))]);
+        let actual = parse(r#"print("a ${b c}")"#);
+        assert!(actual.is_err());
+    }
+
+    #[test]
+    fn parser_empty_interpolation_test() {
+        assert_eq!(
+            parse(r#"print("${}")"#),
+            Err(ParseError::EmptyInterpolation { span: Span { line: 1, column: 8, start: 7, len: 3 } }),
+        );
+        assert_eq!(
+            parse(r#"print("a ${ } ${b}")"#),
+            Err(ParseError::EmptyInterpolation { span: Span { line: 1, column: 10, start: 9, len: 4 } }),
+        );
+        // A string inside the braces is not the end of the outer one
+        assert!(parse(r#"print("${"}"}")"#).is_ok());
+    }
+
+    #[test]
+    fn parser_doc_comments_test() {
+        let actual = parse(r#"/// The answer
+        let answer = 42
+
+        /// Adds two numbers.
+        ///
+        /// Both must be ints.
+        fn add(a, b) {
+            /// Not a declaration
+            return a + b
+        }
+        print(add(1, /// lost
+            2))
+        fn sub(a, b) {
+            return a - b
+        }"#).unwrap();
+        assert_eq!(actual[0].docs, vec!["The answer"]);
+        assert_eq!(actual[1].docs, vec!["Adds two numbers.", "", "Both must be ints."]);
+        match &actual[1].kind {
+            StmtKind::Func { body, .. } => assert!(body[0].docs.is_empty()),
+            kind => panic!("expected a function, found {:?}", kind),
+        }
+        assert!(actual[2].docs.is_empty());
+        assert!(actual[3].docs.is_empty());
+    }
+
+    #[test]
+    fn parser_power_and_modulo_test() {
+        let actual = parse("print(-2 ** 3 ** 2 % 5)").unwrap();
+        assert_eq!(actual, vec![stmt(StmtKind::Print(binary(
+            BinaryOp::Mod,
+            unary(UnaryOp::Neg, binary(
+                BinaryOp::Pow,
+                int(2),
+                binary(BinaryOp::Pow, int(3), int(2)),
+            )),
+            int(5),
+        )))]);
+    }
+
+    #[test]
+    fn parser_bitwise_test() {
+        let actual = parse("print(a | b ^ c & ~d << 1 == 0)").unwrap();
+        assert_eq!(actual, vec![stmt(StmtKind::Print(binary(
+            BinaryOp::Eq,
+            binary(
+                BinaryOp::BitOr,
+                var("a"),
+                binary(
+                    BinaryOp::BitXor,
+                    var("b"),
+                    binary(
+                        BinaryOp::BitAnd,
+                        var("c"),
+                        binary(BinaryOp::Shl, unary(UnaryOp::BitNot, var("d")), int(1)),
+                    ),
+                ),
+            ),
+            int(0),
+        )))]);
+    }
+
+    #[test]
+    fn parser_compound_assignment_test() {
+        let actual = parse("x += 1\nx -= 2 * y\nx *= 3\nx /= 4").unwrap();
+        assert_eq!(actual, vec![
+            stmt(StmtKind::Assign { name: "x", value: binary(BinaryOp::Add, var("x"), int(1)) }),
+            stmt(StmtKind::Assign {
+                name: "x",
+                value: binary(BinaryOp::Sub, var("x"), binary(BinaryOp::Mul, int(2), var("y"))),
+            }),
+            stmt(StmtKind::Assign { name: "x", value: binary(BinaryOp::Mul, var("x"), int(3)) }),
+            stmt(StmtKind::Assign { name: "x", value: binary(BinaryOp::Div, var("x"), int(4)) }),
+        ]);
+        assert!(parse("1 += 2").is_err());
+    }
+
+    #[test]
+    fn parser_loops_test() {
+        let actual = parse(r#"while i < 10 {
+            i = i + 1
+        }
+        for n in 0..10 {
+            if n == 5 {
+                break
+            }
+            continue
+        }"#).unwrap();
+        assert_eq!(actual, vec![
+            stmt(StmtKind::While {
+                cond: binary(BinaryOp::Lt, var("i"), int(10)),
+                body: vec![stmt(StmtKind::Assign {
+                    name: "i",
+                    value: binary(BinaryOp::Add, var("i"), int(1)),
+                })],
+            }),
+            stmt(StmtKind::For {
+                var: "n",
+                iterable: expr(ExprKind::Range {
+                    start: Box::new(int(0)),
+                    end: Box::new(int(10)),
+                }),
+                body: vec![
+                    stmt(StmtKind::If {
+                        cond: binary(BinaryOp::Eq, var("n"), int(5)),
+                        then_branch: vec![stmt(StmtKind::Break)],
+                        else_branch: None,
+                    }),
+                    stmt(StmtKind::Continue),
+                ],
+            }),
+        ]);
+    }
+
+    #[test]
+    fn parser_node_spans_test() {
+        let actual = parse("let x = 1
+print(x + 22)").unwrap();
+        assert_eq!(actual[0].span, Span { line: 1, column: 1, start: 0, len: 9 });
+        assert_eq!(actual[1].span, Span { line: 2, column: 1, start: 10, len: 13 });
+        match &actual[1].kind {
+            StmtKind::Print(value) => {
+                assert_eq!(value.span, Span { line: 2, column: 7, start: 16, len: 6 })
+            }
+            kind => panic!("expected a print statement, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parser_missing_closing_bracket_test() {
+        let actual = parse("if a {\n print(a)\n");
+        assert_eq!(actual, Err(ParseError::UnexpectedEof {
+            expected: "`}`",
+            span: Span { line: 3, column: 1, start: 17, len: 0 },
+        }));
+    }
+
+    #[test]
+    fn parser_two_statements_on_one_line_test() {
+        let actual = parse("let a = 1 let b = 2");
+        assert_eq!(actual, Err(ParseError::UnexpectedToken {
+            expected: "end of line",
+            found: "`let`".to_string(),
+            span: Span { line: 1, column: 11, start: 10, len: 3 },
+        }));
+    }
+
+    #[test]
+    fn parser_error_position_test() {
+        let actual = parse("let tên = 1\nprint(tên +)").unwrap_err();
+        assert_eq!(actual.to_string(), "2:12: expected expression, found `)`");
+        let actual = parse("print(1 +\n2)").unwrap_err();
+        assert_eq!(actual.to_string(), "1:10: expected expression, found end of line");
+    }
+
+    #[test]
+    fn parser_reports_all_lex_errors_test() {
+        let actual = Parser::new(Lexer::new("let a = 1 @ 2\nlet b = 'oops")).parse();
+        assert_eq!(actual, Err(vec![
+            ParseError::Lex(LexError::UnexpectedChar {
+                c: '@',
+                span: Span { line: 1, column: 11, start: 10, len: 1 },
+            }),
+            ParseError::Lex(LexError::UnterminatedString {
+                span: Span { line: 2, column: 9, start: 22, len: 5 },
+            }),
+        ]));
+    }
+
+    #[test]
+    fn parser_diagnostic_test() {
+        let error = parse("let a = 1 let b = 2").unwrap_err();
+        let actual = error.to_diagnostic().render("main.gust", "let a = 1 let b = 2", false);
+        assert_eq!(actual, "error[E0101]: expected end of line, found `let`
+ --> main.gust:1:11
+  |
+1 | let a = 1 let b = 2
+  |           ^^^
+  |
+  = help: put every statement on its own line
+");
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::{HashMap, HashSet};
+use crate::bytecode::{FUNC_PARAM_OFFSET, GLOBALS_SIZE};
+use super::ast::{Stmt, StmtKind};
+use super::codegen::{CompileError, CompileResult};
+use super::token::Span;
+
+// The Resolver decides where every variable lives at runtime.
+//
+// The `let`s at the top level of a program declare globals. Everything
+// declared inside a block `{}`, and everything in a function, is a local
+// in the current call frame: the parameters are below the frame pointer,
+// the locals start at the frame pointer. A block can shadow the names of
+// the enclosing blocks, and gives its slots back when it ends, so the
+// next block reuses them.
+//
+// A name is looked up from the innermost block out to the globals. The
+// `let`s of the enclosing blocks that haven't been reached yet are known
+// too, to tell a variable used before its declaration from a variable that
+// doesn't exist.
+//
+// The globals of a chunk get their slots before anything is compiled, so
+// the functions, which are compiled first, can use every global of the
+// program. The top-level code still only sees a new global from its `let`
+// on.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slot {
+    Global(i32),
+    // The offset from the frame pointer
+    Local(i32),
+}
+
+#[derive(Clone)]
+struct Scope {
+    // The names and offsets of the variables, in declaration order
+    variables: Vec<(String, i32)>,
+    // The names declared further down in the block
+    pending: HashSet<String>,
+    // The first local slot of the block, free again once the block ends
+    first_local: i32,
+}
+
+// The Resolver keeps the globals of every chunk it has seen, like the
+// CodeGenerator that owns it.
+#[derive(Clone, Default)]
+pub struct Resolver {
+    globals: HashMap<String, i32>,
+    // The globals declared by the chunk being compiled so far, and the new
+    // ones it declares further down
+    chunk_globals: HashSet<String>,
+    pending_globals: HashSet<String>,
+    // The blocks around the statement being compiled, the innermost last
+    scopes: Vec<Scope>,
+    in_function: bool,
+    next_local: i32,
+    // The most local slots used at once, since the start of the function
+    // or of the top-level code
+    frame_size: i32,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Start a new chunk, and give its globals their slots. The globals of
+    // the previous chunks can be declared again, like a REPL user would
+    // expect.
+    pub fn begin_chunk(&mut self, program: &[Stmt]) -> CompileResult<()> {
+        self.chunk_globals.clear();
+        self.pending_globals.clear();
+        // In the order of the `let`s, so the slots don't depend on the
+        // order of a HashSet
+        for stmt in program {
+            if let StmtKind::Let { name, .. } = &stmt.kind {
+                if !self.globals.contains_key(*name) {
+                    self.new_global(name, stmt.span)?;
+                    self.pending_globals.insert(name.to_string());
+                }
+            }
+        }
+        self.scopes.clear();
+        self.in_function = false;
+        self.next_local = 0;
+        self.frame_size = 0;
+        Ok(())
+    }
+
+    // The next free slot of the globals, for a new name
+    fn new_global(&mut self, name: &str, span: Span) -> CompileResult<i32> {
+        if self.globals.len() >= GLOBALS_SIZE {
+            return Err(CompileError::TooManyGlobals { limit: GLOBALS_SIZE, span });
+        }
+        let slot = self.globals.len() as i32;
+        self.globals.insert(name.to_string(), slot);
+        Ok(slot)
+    }
+
+    // The parameters and the top-level `let`s of a function share the same
+    // scope, so a `let` can't redeclare a parameter
+    pub fn begin_function(&mut self, params: &[&str], body: &[Stmt], span: Span) -> CompileResult<()> {
+        self.in_function = true;
+        self.next_local = 0;
+        self.frame_size = 0;
+        let argc = params.len() as i32;
+        let mut scope = Scope {
+            variables: vec![],
+            pending: declared_names(body),
+            first_local: 0,
+        };
+        for (index, param) in params.iter().enumerate() {
+            if scope.variables.iter().any(|(name, _)| name == param) {
+                return Err(CompileError::DuplicateVariable { name: param.to_string(), span });
+            }
+            scope.variables.push((param.to_string(), -(FUNC_PARAM_OFFSET + argc - index as i32)));
+        }
+        self.scopes = vec![scope];
+        Ok(())
+    }
+
+    pub fn end_function(&mut self) {
+        self.scopes.clear();
+        self.in_function = false;
+        self.next_local = 0;
+        self.frame_size = 0;
+    }
+
+    pub fn in_function(&self) -> bool {
+        self.in_function
+    }
+
+    // The number of local slots the frame of the function, or of the
+    // top-level code, needs for everything declared so far
+    pub fn frame_size(&self) -> usize {
+        self.frame_size as usize
+    }
+
+    pub fn begin_block(&mut self, body: &[Stmt]) {
+        self.scopes.push(Scope {
+            variables: vec![],
+            pending: declared_names(body),
+            first_local: self.next_local,
+        });
+    }
+
+    pub fn end_block(&mut self) {
+        if let Some(scope) = self.scopes.pop() {
+            self.next_local = scope.first_local;
+        }
+    }
+
+    // Give a new variable its slot in the current scope
+    pub fn declare(&mut self, name: &str, span: Span) -> CompileResult<Slot> {
+        let next_local = self.next_local;
+        let scope = match self.scopes.last_mut() {
+            Some(scope) => scope,
+            None => {
+                if !self.chunk_globals.insert(name.to_string()) {
+                    return Err(CompileError::DuplicateVariable { name: name.to_string(), span });
+                }
+                self.pending_globals.remove(name);
+                return match self.globals.get(name) {
+                    Some(slot) => Ok(Slot::Global(*slot)),
+                    None => self.new_global(name, span).map(Slot::Global),
+                };
+            }
+        };
+        if scope.variables.iter().any(|(variable, _)| variable == name) {
+            return Err(CompileError::DuplicateVariable { name: name.to_string(), span });
+        }
+        scope.pending.remove(name);
+        scope.variables.push((name.to_string(), next_local));
+        self.next_local += 1;
+        self.frame_size = self.frame_size.max(self.next_local);
+        Ok(Slot::Local(next_local))
+    }
+
+    pub fn resolve(&self, name: &str, span: Span) -> CompileResult<Slot> {
+        for scope in self.scopes.iter().rev() {
+            if let Some((_, offset)) = scope.variables.iter().rev().find(|(variable, _)| variable == name) {
+                return Ok(Slot::Local(*offset));
+            }
+        }
+        let hidden = !self.in_function && self.pending_globals.contains(name);
+        if let Some(slot) = self.globals.get(name).filter(|_| !hidden) {
+            return Ok(Slot::Global(*slot));
+        }
+        if hidden || self.scopes.iter().any(|scope| scope.pending.contains(name)) {
+            Err(CompileError::UsedBeforeDeclaration { name: name.to_string(), span })
+        } else {
+            Err(CompileError::UndefinedVariable { name: name.to_string(), span })
+        }
+    }
+}
+
+// The names declared by the `let`s of a block, not the ones of the blocks
+// nested in it
+fn declared_names(body: &[Stmt]) -> HashSet<String> {
+    body.iter()
+        .filter_map(|stmt| match &stmt.kind {
+            StmtKind::Let { name, .. } => Some(name.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Resolver, Slot};
+    use crate::compiler::{codegen::CompileError, lexer::Lexer, parser::Parser, token::Span};
+
+    #[test]
+    fn resolver_slots_test() {
+        let span = Span::default();
+        let mut resolver = Resolver::new();
+        resolver.begin_chunk(&[]).unwrap();
+        assert_eq!(resolver.declare("x", span), Ok(Slot::Global(0)));
+        resolver.begin_block(&[]);
+        assert_eq!(resolver.declare("x", span), Ok(Slot::Local(0)));
+        assert_eq!(resolver.declare("y", span), Ok(Slot::Local(1)));
+        resolver.begin_block(&[]);
+        assert_eq!(resolver.declare("x", span), Ok(Slot::Local(2)));
+        assert_eq!(resolver.resolve("x", span), Ok(Slot::Local(2)));
+        assert_eq!(resolver.resolve("y", span), Ok(Slot::Local(1)));
+        resolver.end_block();
+        assert_eq!(resolver.resolve("x", span), Ok(Slot::Local(0)));
+        resolver.end_block();
+        assert_eq!(resolver.resolve("x", span), Ok(Slot::Global(0)));
+        // The slots of a block are reused by the next one
+        resolver.begin_block(&[]);
+        assert_eq!(resolver.declare("z", span), Ok(Slot::Local(0)));
+        resolver.end_block();
+        assert_eq!(resolver.frame_size(), 3);
+        assert_eq!(
+            resolver.resolve("y", span),
+            Err(CompileError::UndefinedVariable { name: "y".to_string(), span }),
+        );
+        assert_eq!(
+            resolver.declare("x", span),
+            Err(CompileError::DuplicateVariable { name: "x".to_string(), span }),
+        );
+        // A new chunk can declare the globals again
+        resolver.begin_chunk(&[]).unwrap();
+        assert_eq!(resolver.declare("x", span), Ok(Slot::Global(0)));
+        assert_eq!(resolver.declare("w", span), Ok(Slot::Global(1)));
+    }
+
+    #[test]
+    fn resolver_function_reads_global_test() {
+        let span = Span::default();
+        let program = Parser::new(Lexer::new("let a = 1\nlet count = 2")).parse().unwrap();
+        let mut resolver = Resolver::new();
+        resolver.begin_chunk(&program).unwrap();
+        // The functions see every global of the chunk
+        resolver.begin_function(&[], &[], span).unwrap();
+        assert_eq!(resolver.resolve("count", span), Ok(Slot::Global(1)));
+        resolver.end_function();
+        // The top-level code only from their `let` on
+        assert_eq!(
+            resolver.resolve("count", span),
+            Err(CompileError::UsedBeforeDeclaration { name: "count".to_string(), span }),
+        );
+        assert_eq!(resolver.declare("a", span), Ok(Slot::Global(0)));
+        assert_eq!(resolver.declare("count", span), Ok(Slot::Global(1)));
+        assert_eq!(resolver.resolve("count", span), Ok(Slot::Global(1)));
+    }
+
+    #[test]
+    fn resolver_function_test() {
+        let span = Span::default();
+        let mut resolver = Resolver::new();
+        resolver.begin_function(&["a", "b"], &[], span).unwrap();
+        assert_eq!(resolver.resolve("a", span), Ok(Slot::Local(-5)));
+        assert_eq!(resolver.resolve("b", span), Ok(Slot::Local(-4)));
+        assert_eq!(resolver.declare("c", span), Ok(Slot::Local(0)));
+        assert_eq!(
+            resolver.declare("a", span),
+            Err(CompileError::DuplicateVariable { name: "a".to_string(), span }),
+        );
+        resolver.end_function();
+        assert_eq!(
+            resolver.begin_function(&["a", "a"], &[], span),
+            Err(CompileError::DuplicateVariable { name: "a".to_string(), span }),
+        );
+    }
+
+    #[test]
+    fn resolver_too_many_globals_test() {
+        let source = (0..1025).map(|i| format!("let v{} = {}\n", i, i)).collect::<String>();
+        let program = Parser::new(Lexer::new(&source)).parse().unwrap();
+        let mut resolver = Resolver::new();
+        // The `let` of the first global past the limit
+        assert_eq!(
+            resolver.begin_chunk(&program),
+            Err(CompileError::TooManyGlobals { limit: 1024, span: program[1024].span }),
+        );
+        let mut resolver = Resolver::new();
+        resolver.begin_chunk(&program[..1024]).unwrap();
+        let span = Span::default();
+        assert_eq!(resolver.declare("v1023", span), Ok(Slot::Global(1023)));
+        assert_eq!(
+            resolver.declare("w", span),
+            Err(CompileError::TooManyGlobals { limit: 1024, span }),
+        );
+    }
+}
@@ -1,6 +1,8 @@
-#[derive(Debug, PartialEq)]
+use std::borrow::Cow;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token<'a> {
-    Invalid,
     EOL,
 
     // Single char tokens
@@ -12,21 +14,34 @@ pub enum Token<'a> {
     RightSquareBracket,
     Comma,
     Dot,
+    DotDot,
+    Colon,
+    Percent,
+    Caret,
+    Tilde,
+
+    // Conditional tokens
     Minus,
+    MinusEqual,
     Plus,
-    Colon,
+    PlusEqual,
     Slash,
+    SlashEqual,
     Star,
-
-    // Conditional tokens
+    StarEqual,
+    StarStar,
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
+    Ampersand,
+    Pipe,
 
     // Keywords
     And,
@@ -35,7 +50,10 @@ pub enum Token<'a> {
     Else,
     Func,
     For,
+    In,
     While,
+    Break,
+    Continue,
     Let,
     Nil,
     Return,
@@ -45,6 +63,41 @@ pub enum Token<'a> {
 
     // Others
     Identifier(&'a str),
-    String(&'a str),
-    Number(&'a str),
+    // The value of a string literal, with its escapes replaced. It is only
+    // an owned string when there are escapes in the literal.
+    String(Cow<'a, str>),
+    // The part of an interpolated string before a `${`. The tokens of the
+    // expression come next, then the rest of the string, starting after the
+    // `}`, as another Interpolation or as the final String.
+    Interpolation(Cow<'a, str>),
+    Int(i64),
+    Float(f64),
+    // The text of a `///` comment, after the slashes and the space
+    DocComment(&'a str),
+}
+
+// Where a token is in the source: `line` and `column` start from 1 and
+// `column` counts chars, `start` and `len` are in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub start: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken<'a> {
+    pub token: Token<'a>,
+    pub span: Span,
+}
+
+impl Span {
+    // A span that covers from the start of this one to the end of `end`
+    pub fn to(self, end: Span) -> Span {
+        Span {
+            len: (end.start + end.len).saturating_sub(self.start),
+            ..self
+        }
+    }
 }
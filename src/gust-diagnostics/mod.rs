@@ -0,0 +1,199 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use crate::compiler::token::Span;
+
+// Every error in the pipeline, from the lexer down to the runtime, can be
+// turned into a Diagnostic, and they are all rendered the same way:
+//
+//   error[E0201]: undefined variable `x`
+//    --> hello.gust:2:7
+//     |
+//   2 | print(x + 1)
+//     |       ^
+//     |
+//     = help: declare it first with `let x = ...`
+//
+// The error codes are grouped by the stage that reports them:
+// - E00xx: the lexer
+// - E01xx: the parser
+// - E02xx: the compiler
+// - E03xx: the virtual machine
+// - E04xx: the bytecode verifier
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+    pub help: Option<String>,
+}
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            span: None,
+            help: None,
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    pub fn render(&self, file: &str, source: &str, color: bool) -> String {
+        let paint = |style: &str, text: &str| {
+            if color {
+                format!("{}{}{}", style, text, RESET)
+            } else {
+                text.to_string()
+            }
+        };
+        let mut out = format!(
+            "{}{}\n",
+            paint(RED, &format!("error[{}]", self.code)),
+            paint(BOLD, &format!(": {}", self.message)),
+        );
+        // A span on line 0 doesn't point into the source, like the ones of
+        // the code the compiler adds
+        let span = match self.span {
+            Some(span) if span.line > 0 => span,
+            _ => {
+                out.push_str(&format!("{} {}\n", paint(BLUE, "-->"), file));
+                return self.render_help(out, 0, &paint);
+            }
+        };
+        let line_number = span.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let line = source.lines().nth(span.line - 1).unwrap_or("");
+        out.push_str(&format!(
+            "{}{} {}:{}:{}\n",
+            gutter,
+            paint(BLUE, "-->"),
+            file,
+            span.line,
+            span.column
+        ));
+        out.push_str(&format!("{} {}\n", gutter, paint(BLUE, "|")));
+        out.push_str(&format!("{} {} {}\n", paint(BLUE, &line_number), paint(BLUE, "|"), line));
+        // Keep the tabs before the error, so the carets line up with it
+        let padding = line
+            .chars()
+            .take(span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        // A span that goes past the end of the line is only underlined
+        // until the end of it, an empty one still gets a single caret
+        let rest = line.chars().skip(span.column.saturating_sub(1)).count();
+        let width = source
+            .get(span.start..span.start + span.len)
+            .map_or(span.len, |text| text.chars().count())
+            .min(rest)
+            .max(1);
+        out.push_str(&format!(
+            "{} {} {}{}\n",
+            gutter,
+            paint(BLUE, "|"),
+            padding,
+            paint(RED, &"^".repeat(width))
+        ));
+        self.render_help(out, gutter.len(), &paint)
+    }
+
+    fn render_help(&self, mut out: String, gutter: usize, paint: &dyn Fn(&str, &str) -> String) -> String {
+        if let Some(help) = &self.help {
+            let gutter = " ".repeat(gutter);
+            out.push_str(&format!("{} {}\n", gutter, paint(BLUE, "|")));
+            out.push_str(&format!("{} {} {}\n", gutter, paint(BLUE, "="), paint(BOLD, &format!("help: {}", help))));
+        }
+        out
+    }
+}
+
+// Print the diagnostics to stderr, with colour if it is a terminal and
+// NO_COLOR is not set
+pub fn emit(diagnostics: &[Diagnostic], file: &str, source: &str) {
+    let stderr = io::stderr();
+    let color = stderr.is_terminal() && env::var_os("NO_COLOR").is_none();
+    let mut stderr = stderr.lock();
+    for diagnostic in diagnostics {
+        let _ = writeln!(stderr, "{}", diagnostic.render(file, source, color));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Diagnostic;
+    use crate::compiler::token::Span;
+
+    #[test]
+    fn render_with_span_test() {
+        let diagnostic = Diagnostic::error("E0201", "undefined variable `x`")
+            .with_span(Span { line: 2, column: 7, start: 16, len: 1 })
+            .with_help("declare it first with `let x = ...`");
+        let actual = diagnostic.render("hello.gust", "let y = 1\nprint(x + y)", false);
+        assert_eq!(actual, "error[E0201]: undefined variable `x`
+ --> hello.gust:2:7
+  |
+2 | print(x + y)
+  |       ^
+  |
+  = help: declare it first with `let x = ...`
+");
+    }
+
+    #[test]
+    fn render_wide_span_test() {
+        let source = "let tên = 1\n\n\n\n\n\n\n\n\nlet y = 'oops";
+        let diagnostic = Diagnostic::error("E0001", "unterminated string")
+            .with_span(Span { line: 10, column: 9, start: 29, len: 5 });
+        let actual = diagnostic.render("x.gust", source, false);
+        assert_eq!(actual, "error[E0001]: unterminated string
+  --> x.gust:10:9
+   |
+10 | let y = 'oops
+   |         ^^^^^
+");
+    }
+
+    #[test]
+    fn render_end_of_file_test() {
+        let diagnostic = Diagnostic::error("E0102", "expected `}`, found end of file")
+            .with_span(Span { line: 2, column: 10, start: 16, len: 0 });
+        let actual = diagnostic.render("f.gust", "if a {\n print(a)", false);
+        assert!(actual.ends_with("2 |  print(a)\n  |          ^\n"));
+    }
+
+    #[test]
+    fn render_color_test() {
+        let diagnostic = Diagnostic::error("E0002", "unexpected character `@`");
+        let actual = diagnostic.render("f.gust", "", true);
+        assert!(actual.starts_with("\x1b[1;31merror[E0002]\x1b[0m"));
+        let actual = diagnostic.render("f.gust", "", false);
+        assert_eq!(actual, "error[E0002]: unexpected character `@`\n--> f.gust\n");
+    }
+
+    #[test]
+    fn render_line_zero_test() {
+        let diagnostic = Diagnostic::error("E0302", "stack overflow");
+        let expected = diagnostic.render("f.gust", "print(1)", false);
+        let actual = diagnostic.with_span(Span::default()).render("f.gust", "print(1)", false);
+        assert_eq!(actual, expected);
+        let diagnostic = Diagnostic::error("E0302", "stack overflow")
+            .with_span(Span { line: 1, column: 0, start: 0, len: 1 });
+        let actual = diagnostic.render("f.gust", "print(1)", false);
+        assert!(actual.ends_with("1 | print(1)\n  | ^\n"));
+    }
+}
@@ -0,0 +1,194 @@
+use std::io::{self, BufRead, Write};
+use crate::compiler::{ast::{Stmt, StmtKind}, codegen::CodeGenerator, lexer::Lexer, parser::Parser, token::Token};
+use crate::diagnostics::{self, Diagnostic};
+use crate::vm::VirtualMachine;
+
+// The REPL compiles every input as a new chunk of code and runs it on
+// the same VirtualMachine, so the globals and functions declared by the
+// previous inputs are still there:
+//
+//   > let x = 10
+//   > print(x + 1)
+//   11
+//
+// An input that opens more `{` than it closes is not complete yet, the
+// REPL keeps reading lines until the brackets are balanced.
+//
+// The value of an expression statement is printed, instead of thrown away.
+
+pub struct Repl {
+    generator: CodeGenerator,
+    vm: VirtualMachine,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        let mut vm = VirtualMachine::new();
+        vm.set_quiet(true);
+        Self {
+            generator: CodeGenerator::new(),
+            vm,
+        }
+    }
+
+    pub fn eval(&mut self, source: &str, stdout: &mut dyn io::Write) -> Result<(), Vec<Diagnostic>> {
+        let program = Parser::new(Lexer::new(source))
+            .parse()
+            .map_err(|errors| errors.iter().map(|err| err.to_diagnostic()).collect::<Vec<Diagnostic>>())?
+            .into_iter()
+            .map(|stmt| match stmt.kind {
+                StmtKind::Expr(expr) => Stmt::new(StmtKind::Print(expr), stmt.span),
+                _ => stmt,
+            })
+            .collect::<Vec<Stmt>>();
+        let start = self.vm.program_len();
+        let entrypoint = self
+            .generator
+            .compile_chunk(&program)
+            .map_err(|err| vec![err.to_diagnostic()])?;
+        self.vm.append_program(self.generator.code()[start..].to_vec(), entrypoint);
+        self.vm.set_constants(self.generator.constants().to_vec());
+        self.vm.run(stdout).map_err(|err| {
+            let mut diagnostic = err.to_diagnostic();
+            // Errors in a function from a previous input can't be shown
+            // in the source of this one
+            match self.generator.span_at(err.ip()) {
+                Some(span) if err.ip() >= start => diagnostic = diagnostic.with_span(span),
+                _ => {}
+            }
+            vec![diagnostic]
+        })
+    }
+}
+
+// Check if every `{` in the input has a matching `}`
+pub fn is_complete(source: &str) -> bool {
+    let mut depth = 0;
+    for spanned in Lexer::new(source).flatten() {
+        match spanned.token {
+            Token::LeftBracket => depth += 1,
+            Token::RightBracket => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+pub fn start() {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut repl = Repl::new();
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { ". " });
+        if stdout.flush().is_err() {
+            break;
+        }
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => input.push_str(&line),
+        }
+        if !is_complete(&input) {
+            continue;
+        }
+        if let Err(errors) = repl.eval(&input, &mut stdout) {
+            diagnostics::emit(&errors, "<repl>", &input);
+        }
+        input.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_complete, Repl};
+
+    #[test]
+    fn repl_globals_between_inputs_test() {
+        let mut stdout = vec![];
+        let mut repl = Repl::new();
+        repl.eval("let x = 10", &mut stdout).unwrap();
+        repl.eval("print(x + 1)", &mut stdout).unwrap();
+        repl.eval("x * 2", &mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "11\n20\n");
+    }
+
+    #[test]
+    fn repl_redeclare_global_test() {
+        let mut stdout = vec![];
+        let mut repl = Repl::new();
+        repl.eval("let x = 1", &mut stdout).unwrap();
+        repl.eval("let x = x + 1", &mut stdout).unwrap();
+        assert!(repl.eval("let y = 1\nlet y = 2", &mut stdout).is_err());
+        repl.eval("if x > 1 {\n let z = x * 10\n print(z)\n}", &mut stdout).unwrap();
+        repl.eval("print(x)", &mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "20\n2\n");
+    }
+
+    #[test]
+    fn repl_functions_between_inputs_test() {
+        let mut stdout = vec![];
+        let mut repl = Repl::new();
+        repl.eval("fn double(n) {\n return n * 2\n}", &mut stdout).unwrap();
+        repl.eval("let y = double(21)", &mut stdout).unwrap();
+        repl.eval("print(y)", &mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "42\n");
+    }
+
+    #[test]
+    fn repl_error_keeps_state_test() {
+        let mut stdout = vec![];
+        let mut repl = Repl::new();
+        repl.eval("let x = 1", &mut stdout).unwrap();
+        assert!(repl.eval("let y = z", &mut stdout).is_err());
+        assert!(repl.eval("print(y)", &mut stdout).is_err());
+        repl.eval("print(x)", &mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn repl_runtime_error_keeps_state_test() {
+        let mut stdout = vec![];
+        let mut repl = Repl::new();
+        repl.eval("let x = 4", &mut stdout).unwrap();
+        let errors = repl.eval("print(x / 0)", &mut stdout).unwrap_err();
+        assert_eq!(errors[0].code, "E0307");
+        repl.eval("print(x)", &mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "4\n");
+    }
+
+    #[test]
+    fn repl_constants_between_inputs_test() {
+        let mut stdout = vec![];
+        let mut repl = Repl::new();
+        repl.eval("let greeting = 'xin chào'", &mut stdout).unwrap();
+        repl.eval("print(2.5)", &mut stdout).unwrap();
+        repl.eval("greeting", &mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "2.5\nxin chào\n");
+    }
+
+    #[test]
+    fn repl_strings_between_inputs_test() {
+        let mut stdout = vec![];
+        let mut repl = Repl::new();
+        repl.eval("let name = 'tên'", &mut stdout).unwrap();
+        repl.eval("let greeting = 'xin chào ' + name", &mut stdout).unwrap();
+        repl.eval("greeting[9..12]", &mut stdout).unwrap();
+        repl.eval("len(greeting)", &mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "tên\n12\n");
+    }
+
+    #[test]
+    fn repl_incomplete_block_test() {
+        assert!(!is_complete("if x > 1 {\n"));
+        assert!(!is_complete("fn f() {\n if x {\n }\n"));
+        assert!(is_complete("fn f() {\n if x {\n }\n}"));
+        assert!(is_complete("let x = 1"));
+    }
+}
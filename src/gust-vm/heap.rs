@@ -0,0 +1,333 @@
+use std::{fmt, mem, time::{Duration, Instant}};
+use super::value::Value;
+
+// The objects that don't fit in a Value live on the heap of the
+// VirtualMachine, the Values only hold a reference to them. For now the
+// only objects are strings, they are never modified after they are
+// created, so a reference can be copied freely.
+//
+// The memory is managed by a mark-and-sweep collector. The VirtualMachine
+// gives it the roots (the values on the stack, in the globals and in the
+// constant pool), every object reachable from them is marked, and the
+// others are freed. Their slots are reused by the next allocations, so an
+// ObjRef is only valid as long as something keeps the object alive.
+//
+// A collection runs when the allocated bytes reach a threshold, which then
+// grows with the size of the live objects, so long running programs don't
+// spend all their time collecting a heap that is mostly alive.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObjRef(usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    String(String),
+}
+
+impl Object {
+    // An estimate of the memory used by the object, to decide when to
+    // collect
+    fn size(&self) -> usize {
+        match self {
+            Object::String(string) => mem::size_of::<Object>() + string.capacity(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcConfig {
+    // The allocated bytes that trigger the first collection, the threshold
+    // never goes below it
+    pub threshold: usize,
+    // After a collection, the next one runs when the heap is this many
+    // times the size of the live objects
+    pub growth_factor: usize,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 1024 * 1024,
+            growth_factor: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub objects_freed: usize,
+    pub bytes_freed: usize,
+    // The time spent in all the collections, and in the last one
+    pub total_pause: Duration,
+    pub last_pause: Duration,
+}
+
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    marks: Vec<bool>,
+    // The slots of the freed objects
+    free: Vec<usize>,
+    // The marked objects whose children are not marked yet
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    config: GcConfig,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self::with_config(GcConfig::default())
+    }
+
+    pub fn with_config(config: GcConfig) -> Self {
+        Self {
+            objects: vec![],
+            marks: vec![],
+            free: vec![],
+            gray: vec![],
+            bytes_allocated: 0,
+            next_gc: config.threshold,
+            config,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.config = config;
+        self.next_gc = config.threshold.max(self.bytes_allocated.saturating_mul(config.growth_factor));
+    }
+
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        self.bytes_allocated += object.size();
+        match self.free.pop() {
+            Some(slot) => {
+                self.objects[slot] = Some(object);
+                ObjRef(slot)
+            }
+            None => {
+                self.objects.push(Some(object));
+                self.marks.push(false);
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    pub fn alloc_string(&mut self, string: String) -> Value {
+        Value::String(self.alloc(Object::String(string)))
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Object {
+        match &self.objects[obj.0] {
+            Some(object) => object,
+            None => panic!("use of a freed object {:?}", obj),
+        }
+    }
+
+    pub fn string(&self, obj: ObjRef) -> &str {
+        match self.get(obj) {
+            Object::String(string) => string,
+        }
+    }
+
+    // Whether the allocated bytes reached the threshold
+    pub fn should_collect(&self) -> bool {
+        self.bytes_allocated >= self.next_gc
+    }
+
+    // Free every object that can't be reached from the roots, and return
+    // the number of bytes freed
+    pub fn collect<'v>(&mut self, roots: impl IntoIterator<Item = &'v Value>) -> usize {
+        let start = Instant::now();
+        for root in roots {
+            self.mark_value(root);
+        }
+        self.trace();
+        let (objects_freed, bytes_freed) = self.sweep();
+        self.next_gc = self
+            .config
+            .threshold
+            .max(self.bytes_allocated.saturating_mul(self.config.growth_factor));
+
+        let pause = start.elapsed();
+        self.stats.collections += 1;
+        self.stats.objects_freed += objects_freed;
+        self.stats.bytes_freed += bytes_freed;
+        self.stats.total_pause += pause;
+        self.stats.last_pause = pause;
+        bytes_freed
+    }
+
+    fn mark_value(&mut self, value: &Value) {
+        if let Value::String(obj) = value {
+            self.mark(*obj);
+        }
+    }
+
+    fn mark(&mut self, obj: ObjRef) {
+        if !self.marks[obj.0] {
+            self.marks[obj.0] = true;
+            self.gray.push(obj);
+        }
+    }
+
+    // Mark the objects referenced by the marked objects, until there is
+    // nothing left to visit
+    fn trace(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            match self.get(obj) {
+                // Strings don't reference other objects
+                Object::String(_) => {}
+            }
+        }
+    }
+
+    fn sweep(&mut self) -> (usize, usize) {
+        let mut objects_freed = 0;
+        let mut bytes_freed = 0;
+        for (slot, object) in self.objects.iter_mut().enumerate() {
+            if mem::take(&mut self.marks[slot]) {
+                continue;
+            }
+            if let Some(object) = object.take() {
+                objects_freed += 1;
+                bytes_freed += object.size();
+                self.free.push(slot);
+            }
+        }
+        self.bytes_allocated -= bytes_freed;
+        (objects_freed, bytes_freed)
+    }
+
+    // The bytes used by the live objects, and the garbage not collected yet
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    // The number of live objects on the heap, not counting the freed slots
+    pub fn object_count(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+}
+
+// A Value together with the heap it points to, so it can be printed
+pub struct Display<'a> {
+    pub value: &'a Value,
+    pub heap: &'a Heap,
+}
+
+impl<'a> fmt::Display for Display<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(n) => write!(f, "{}", n),
+            // Debug keeps the `.0` of the round floats
+            Value::Float(n) => write!(f, "{:?}", n),
+            Value::String(obj) => write!(f, "{}", self.heap.string(*obj)),
+        }
+    }
+}
+
+// Find the byte offsets of the chars from `start` to `end` in a string,
+// so the string is never cut in the middle of a char. `end` can be the
+// number of chars in the string, for a slice that goes to the end.
+pub fn char_range(string: &str, start: usize, end: usize) -> Option<(usize, usize)> {
+    if start > end {
+        return None;
+    }
+    let mut offsets = string.char_indices().map(|(offset, _)| offset).chain(Some(string.len()));
+    let start_offset = offsets.nth(start)?;
+    let end_offset = if end == start {
+        start_offset
+    } else {
+        offsets.nth(end - start - 1)?
+    };
+    Some((start_offset, end_offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use super::{char_range, Display, GcConfig, Heap, Object};
+    use crate::vm::value::Value;
+
+    #[test]
+    fn heap_display_test() {
+        let mut heap = Heap::new();
+        let string = heap.alloc_string("tên".to_string());
+        let display = |value: &Value| Display { value, heap: &heap }.to_string();
+        assert_eq!(display(&string), "tên");
+        assert_eq!(display(&Value::Nil), "nil");
+        assert_eq!(display(&Value::Bool(true)), "true");
+        assert_eq!(display(&Value::Int(-42)), "-42");
+        assert_eq!(display(&Value::Float(2.0)), "2.0");
+        assert_eq!(display(&Value::Float(4.5)), "4.5");
+    }
+
+    #[test]
+    fn heap_collect_test() {
+        let mut heap = Heap::new();
+        let kept = heap.alloc_string("kept".to_string());
+        let garbage = heap.alloc_string("garbage".to_string());
+        let size = mem::size_of::<Object>() + "garbage".len();
+        assert_eq!(heap.object_count(), 2);
+
+        let freed = heap.collect(&[kept, Value::Int(1)]);
+        assert_eq!(freed, size);
+        assert_eq!(heap.object_count(), 1);
+        assert_eq!(kept.display(&heap).to_string(), "kept");
+        assert_eq!(heap.stats().collections, 1);
+        assert_eq!(heap.stats().objects_freed, 1);
+        assert_eq!(heap.stats().bytes_freed, size);
+
+        // The slot of the garbage is reused
+        let reused = heap.alloc_string("new".to_string());
+        assert_eq!(reused, garbage);
+        assert_eq!(reused.display(&heap).to_string(), "new");
+
+        // Nothing is freed twice
+        assert_eq!(heap.collect(&[kept, reused]), 0);
+        assert_eq!(heap.collect(&[]), heap.stats().bytes_freed - size);
+        assert_eq!(heap.object_count(), 0);
+        assert_eq!(heap.bytes_allocated(), 0);
+    }
+
+    #[test]
+    fn heap_threshold_test() {
+        let mut heap = Heap::with_config(GcConfig { threshold: 100, growth_factor: 2 });
+        let string = heap.alloc_string("x".repeat(60));
+        assert!(!heap.should_collect());
+        heap.alloc_string("y".repeat(60));
+        assert!(heap.should_collect());
+
+        // The threshold grows with the live objects
+        heap.collect(&[string]);
+        let live = heap.bytes_allocated();
+        assert!(!heap.should_collect());
+        heap.alloc_string("z".repeat(live * 2));
+        assert!(heap.should_collect());
+    }
+
+    #[test]
+    fn char_range_test() {
+        assert_eq!(char_range("tên", 0, 3), Some((0, 4)));
+        assert_eq!(char_range("tên", 1, 2), Some((1, 3)));
+        assert_eq!(char_range("tên", 2, 2), Some((3, 3)));
+        assert_eq!(char_range("tên", 3, 3), Some((4, 4)));
+        assert_eq!(char_range("tên", 2, 4), None);
+        assert_eq!(char_range("tên", 2, 1), None);
+    }
+}
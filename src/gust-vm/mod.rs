@@ -1,5 +1,14 @@
-use std::io;
-use crate::bytecode::OpCode;
+use std::{cmp::Ordering, convert::TryFrom, fmt, io};
+use crate::bytecode::{
+    verifier::{verify, VerifyError},
+    Constant, InvalidOpcode, OpCode, Program, GLOBALS_SIZE,
+};
+use crate::diagnostics::Diagnostic;
+use heap::{char_range, GcConfig, GcStats, Heap};
+use value::Value;
+
+pub mod heap;
+pub mod value;
 
 // This is a stack-based virtual machine. It is intended to be used to
 // execute bytecodes that produced by the compiler.
@@ -12,14 +21,178 @@ use crate::bytecode::OpCode;
 // - the Argument Count: so we can clean up the arguments in the stack
 // after return.
 //
+// The frame pointer then points right above those 3 values. A function
+// starts with `ENTER n` to reserve its n local slots there, set to nil, so
+// the values it pushes later never overwrite its locals.
+//
+// The stack and the globals hold typed Values, the arithmetic and comparison
+// opcodes check the types of their operands at runtime. Strings are stored
+// on the Heap, the string constants are only copied there once, the first
+// time CONST loads them.
+//
+// The heap is garbage collected before an allocation once it grows past its
+// threshold, or when the program runs the GC opcode. The roots are the
+// values on the stack (the call frames included), the globals and the
+// loaded constants.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    StackUnderflow { ip: usize, opcode: OpCode },
+    StackOverflow { ip: usize, opcode: OpCode },
+    InvalidGlobal { ip: usize, opcode: OpCode, addr: i32 },
+    InvalidLocal { ip: usize, opcode: OpCode, addr: i32 },
+    InvalidJump { ip: usize, opcode: OpCode, addr: i32 },
+    MissingOperand { ip: usize, opcode: OpCode },
+    DivisionByZero { ip: usize, opcode: OpCode },
+    IntegerOverflow { ip: usize, opcode: OpCode },
+    InvalidOpcode { ip: usize, value: i32 },
+    TypeMismatch { ip: usize, opcode: OpCode, left: &'static str, right: &'static str },
+    InvalidOperand { ip: usize, opcode: OpCode, found: &'static str },
+    InvalidConstant { ip: usize, opcode: OpCode, index: i32 },
+    IndexOutOfBounds { ip: usize, opcode: OpCode, index: i64, len: usize },
+    InvalidSlice { ip: usize, opcode: OpCode, start: i64, end: i64, len: usize },
+    NegativeShift { ip: usize, opcode: OpCode, count: i64 },
+    InvalidFrameSize { ip: usize, opcode: OpCode, size: i32 },
+    // The program ran past its last instruction without a HALT
+    EndOfProgram { ip: usize },
+}
+
+impl VmError {
+    // The address of the instruction that failed
+    pub fn ip(&self) -> usize {
+        match self {
+            VmError::StackUnderflow { ip, .. }
+            | VmError::StackOverflow { ip, .. }
+            | VmError::InvalidGlobal { ip, .. }
+            | VmError::InvalidLocal { ip, .. }
+            | VmError::InvalidJump { ip, .. }
+            | VmError::MissingOperand { ip, .. }
+            | VmError::DivisionByZero { ip, .. }
+            | VmError::IntegerOverflow { ip, .. }
+            | VmError::InvalidOpcode { ip, .. }
+            | VmError::TypeMismatch { ip, .. }
+            | VmError::InvalidOperand { ip, .. }
+            | VmError::InvalidConstant { ip, .. }
+            | VmError::IndexOutOfBounds { ip, .. }
+            | VmError::InvalidSlice { ip, .. }
+            | VmError::NegativeShift { ip, .. }
+            | VmError::InvalidFrameSize { ip, .. }
+            | VmError::EndOfProgram { ip } => *ip,
+        }
+    }
+
+    // The error message, without the address
+    pub fn message(&self) -> String {
+        match self {
+            VmError::StackUnderflow { .. } => "stack underflow".to_string(),
+            VmError::StackOverflow { .. } => "stack overflow".to_string(),
+            VmError::InvalidGlobal { addr, .. } => format!("invalid global address {}", addr),
+            VmError::InvalidLocal { addr, .. } => format!("invalid local offset {}", addr),
+            VmError::InvalidJump { addr, .. } => format!("jump to invalid address {}", addr),
+            VmError::MissingOperand { .. } => "missing operand".to_string(),
+            VmError::DivisionByZero { .. } => "division by zero".to_string(),
+            VmError::IntegerOverflow { .. } => "integer overflow".to_string(),
+            VmError::InvalidOpcode { value, .. } => InvalidOpcode(*value).to_string(),
+            VmError::TypeMismatch { opcode, left, right, .. } => {
+                format!("unsupported operand types for {:?}: {} and {}", opcode, left, right)
+            }
+            VmError::InvalidOperand { opcode, found, .. } => {
+                format!("unsupported operand type for {:?}: {}", opcode, found)
+            }
+            VmError::InvalidConstant { index, .. } => format!("invalid constant {}", index),
+            VmError::IndexOutOfBounds { index, len, .. } => {
+                format!("index {} is out of bounds for a length of {}", index, len)
+            }
+            VmError::InvalidSlice { start, end, len, .. } => {
+                format!("slice {}..{} is out of bounds for a length of {}", start, end, len)
+            }
+            VmError::NegativeShift { count, .. } => format!("negative shift count {}", count),
+            VmError::InvalidFrameSize { size, .. } => format!("invalid frame size {}", size),
+            VmError::EndOfProgram { .. } => "reached the end of the program without HALT".to_string(),
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            VmError::StackUnderflow { .. } => Diagnostic::error("E0301", self.message()),
+            VmError::StackOverflow { .. } => Diagnostic::error("E0302", self.message())
+                .with_help("check for a recursive function that never returns"),
+            VmError::InvalidGlobal { .. } => Diagnostic::error("E0303", self.message()),
+            VmError::InvalidLocal { .. } => Diagnostic::error("E0304", self.message()),
+            VmError::InvalidJump { .. } => Diagnostic::error("E0305", self.message()),
+            VmError::MissingOperand { .. } => Diagnostic::error("E0306", self.message()),
+            VmError::DivisionByZero { .. } => Diagnostic::error("E0307", self.message()),
+            VmError::IntegerOverflow { .. } => Diagnostic::error("E0308", self.message()),
+            VmError::EndOfProgram { .. } => Diagnostic::error("E0309", self.message()),
+            VmError::InvalidOpcode { .. } => Diagnostic::error("E0310", self.message()),
+            VmError::TypeMismatch { .. } => Diagnostic::error("E0311", self.message()),
+            VmError::InvalidOperand { .. } => Diagnostic::error("E0312", self.message()),
+            VmError::InvalidConstant { .. } => Diagnostic::error("E0313", self.message()),
+            VmError::IndexOutOfBounds { .. } => Diagnostic::error("E0314", self.message())
+                .with_help("strings are indexed by chars, starting from 0"),
+            VmError::InvalidSlice { .. } => Diagnostic::error("E0315", self.message())
+                .with_help("strings are indexed by chars, starting from 0"),
+            VmError::NegativeShift { .. } => Diagnostic::error("E0316", self.message())
+                .with_help("use the other shift operator to shift in the other direction"),
+            VmError::InvalidFrameSize { .. } => Diagnostic::error("E0317", self.message()),
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow { ip, opcode }
+            | VmError::StackOverflow { ip, opcode }
+            | VmError::InvalidGlobal { ip, opcode, .. }
+            | VmError::InvalidLocal { ip, opcode, .. }
+            | VmError::InvalidJump { ip, opcode, .. }
+            | VmError::MissingOperand { ip, opcode }
+            | VmError::DivisionByZero { ip, opcode }
+            | VmError::IntegerOverflow { ip, opcode }
+            | VmError::TypeMismatch { ip, opcode, .. }
+            | VmError::InvalidOperand { ip, opcode, .. }
+            | VmError::InvalidConstant { ip, opcode, .. }
+            | VmError::IndexOutOfBounds { ip, opcode, .. }
+            | VmError::InvalidSlice { ip, opcode, .. }
+            | VmError::NegativeShift { ip, opcode, .. }
+            | VmError::InvalidFrameSize { ip, opcode, .. } => {
+                write!(f, "{:04} {:?}: {}", ip, opcode, self.message())
+            }
+            VmError::InvalidOpcode { ip, .. } | VmError::EndOfProgram { ip } => {
+                write!(f, "{:04}: {}", ip, self.message())
+            }
+        }
+    }
+}
+
+pub type VmResult<T> = Result<T, VmError>;
 
 pub struct VirtualMachine {
     program: Vec<i32>,
+    constants: Vec<Constant>,
+    // The values of the constants already loaded by CONST
+    constant_values: Vec<Option<Value>>,
+    heap: Heap,
     ip: usize,
     sp: usize,
     fp: usize,
-    stack: Vec<i32>,
-    globals: Vec<i32>,
+    stack: Vec<Value>,
+    globals: Vec<Value>,
+    // Don't say BYE! on HALT, the REPL runs one chunk after another
+    quiet: bool,
+    // The instruction being executed, for the errors
+    op_ip: usize,
+    opcode: OpCode,
+    // The program passed the verifier, its operands, jumps, addresses and
+    // stack depths don't need to be checked again
+    verified: bool,
+}
+
+impl Default for VirtualMachine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VirtualMachine {
@@ -28,187 +201,597 @@ impl VirtualMachine {
             ip: 0,
             sp: 0,
             fp: 0,
-            stack: vec![0; 1024],
-            globals: vec![0; 1024],
+            stack: vec![Value::Nil; 1024],
+            globals: vec![Value::Nil; GLOBALS_SIZE],
             program: vec![],
+            constants: vec![],
+            constant_values: vec![],
+            heap: Heap::new(),
+            quiet: false,
+            op_ip: 0,
+            opcode: OpCode::HALT,
+            verified: false,
         }
     }
 
     pub fn load_program(&mut self, program: Vec<i32>, entrypoint: usize) {
         self.program = program;
         self.ip = entrypoint;
+        self.verified = false;
+    }
+
+    // Verify the program before loading it, a program that doesn't pass
+    // is not loaded. The verifier checks the stack from an empty one, so
+    // the values left by the code that ran before are dropped.
+    pub fn load_verified(&mut self, program: &Program) -> Result<(), VerifyError> {
+        verify(program)?;
+        self.load_program(program.code.clone(), program.entrypoint);
+        self.sp = 0;
+        self.fp = 0;
+        self.set_constants(program.constants.clone());
+        self.verified = true;
+        Ok(())
+    }
+
+    // Add a chunk of code at the end of the current program, the globals
+    // and everything loaded before stay where they are. The entrypoint is
+    // an address in the whole program, not in the chunk.
+    pub fn append_program(&mut self, chunk: Vec<i32>, entrypoint: usize) {
+        self.program.extend(chunk);
+        self.ip = entrypoint;
+        self.verified = false;
+    }
+
+    // The constant pool for the program, and the chunks appended to it
+    pub fn set_constants(&mut self, constants: Vec<Constant>) {
+        // The REPL only adds constants at the end, the ones already
+        // loaded are still valid
+        if !constants.starts_with(&self.constants) {
+            self.constant_values.clear();
+        }
+        self.constant_values.resize(constants.len(), None);
+        self.constants = constants;
+    }
+
+    pub fn program_len(&self) -> usize {
+        self.program.len()
+    }
+
+    pub fn set_gc_config(&mut self, config: GcConfig) {
+        self.heap.set_config(config);
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        self.heap.stats()
+    }
+
+    // The bytes used by the objects on the heap
+    pub fn heap_size(&self) -> usize {
+        self.heap.bytes_allocated()
+    }
+
+    pub fn heap_objects(&self) -> usize {
+        self.heap.object_count()
+    }
+
+    // Run a collection now, and return the number of bytes freed
+    pub fn collect_garbage(&mut self) -> usize {
+        let roots = self.stack[..self.sp]
+            .iter()
+            .chain(&self.globals)
+            .chain(self.constant_values.iter().flatten());
+        self.heap.collect(roots)
+    }
+
+    fn alloc_string(&mut self, string: String) -> Value {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc_string(string)
+    }
+
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
     }
 
-    pub fn pop_stack(&mut self) -> i32 {
+    pub fn pop_stack(&mut self) -> VmResult<Value> {
+        if self.sp == 0 && !self.verified {
+            return Err(VmError::StackUnderflow { ip: self.op_ip, opcode: self.opcode });
+        }
         self.sp -= 1;
-        return self.stack[self.sp];
+        Ok(std::mem::take(&mut self.stack[self.sp]))
     }
 
-    pub fn push_stack(&mut self, val: i32) {
+    pub fn push_stack(&mut self, val: Value) -> VmResult<()> {
+        if self.sp == self.stack.len() {
+            return Err(VmError::StackOverflow { ip: self.op_ip, opcode: self.opcode });
+        }
         self.stack[self.sp] = val;
         self.sp += 1;
+        Ok(())
     }
 
-    pub fn next_operand(&mut self) -> i32 {
+    pub fn next_operand(&mut self) -> VmResult<i32> {
         self.ip += 1;
-        return self.program[self.ip];
+        if self.verified {
+            return Ok(self.program[self.ip]);
+        }
+        match self.program.get(self.ip) {
+            Some(operand) => Ok(*operand),
+            None => Err(VmError::MissingOperand { ip: self.op_ip, opcode: self.opcode }),
+        }
     }
 
-    pub fn run(&mut self, stdout: &mut dyn io::Write) {
+    // Run the program until HALT. On error the stack is cleared, so the
+    // machine can still run another program with the same globals.
+    pub fn run(&mut self, stdout: &mut dyn io::Write) -> VmResult<()> {
+        let result = self.execute(stdout);
+        if result.is_err() {
+            self.sp = 0;
+            self.fp = 0;
+        }
+        result
+    }
+
+    fn execute(&mut self, stdout: &mut dyn io::Write) -> VmResult<()> {
         loop {
-            let opcode = OpCode::from(self.program[self.ip]);
+            let opcode = match self.program.get(self.ip).map(|value| OpCode::try_from(*value)) {
+                Some(Ok(opcode)) => opcode,
+                Some(Err(InvalidOpcode(value))) => return Err(VmError::InvalidOpcode { ip: self.ip, value }),
+                None => return Err(VmError::EndOfProgram { ip: self.ip }),
+            };
+            self.op_ip = self.ip;
+            self.opcode = opcode;
             match opcode {
                 OpCode::HALT => {
-                    if let Err(_) = writeln!(stdout, "BYE!") {
+                    if !self.quiet && writeln!(stdout, "BYE!").is_err() {
                         println!("ERROR: Could not write to output device!");
                     }
                     break;
                 },
                 OpCode::PUSH => {
-                    let val = self.next_operand();
-                    self.push_stack(val);
+                    let val = self.next_operand()?;
+                    self.push_stack(Value::Int(val as i64))?;
+                },
+                OpCode::NIL => self.push_stack(Value::Nil)?,
+                OpCode::TRUE => self.push_stack(Value::Bool(true))?,
+                OpCode::FALSE => self.push_stack(Value::Bool(false))?,
+                OpCode::CONST => {
+                    let index = self.next_operand()?;
+                    let value = self.constant(index)?;
+                    self.push_stack(value)?;
                 },
                 OpCode::ADD => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    self.push_stack(a + b);
+                    if let (Value::String(a), Value::String(b)) = (self.peek(1)?, self.peek(0)?) {
+                        let string = [self.heap.string(a), self.heap.string(b)].concat();
+                        self.sp -= 2;
+                        let value = self.alloc_string(string);
+                        self.push_stack(value)?;
+                    } else {
+                        self.arithmetic(i64::checked_add, |a, b| a + b)?;
+                    }
                 },
-                OpCode::SUB => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    self.push_stack(a - b);
+                OpCode::SUB => self.arithmetic(i64::checked_sub, |a, b| a - b)?,
+                OpCode::MUL => self.arithmetic(i64::checked_mul, |a, b| a * b)?,
+                OpCode::DIV => self.arithmetic(i64::checked_div, |a, b| a / b)?,
+                // The remainder has the sign of the dividend, -7 % 3 is -1
+                OpCode::MOD => self.arithmetic(i64::checked_rem, |a, b| a % b)?,
+                OpCode::POW => self.power()?,
+                OpCode::NEG => {
+                    let value = match self.pop_stack()? {
+                        Value::Int(n) => match n.checked_neg() {
+                            Some(n) => Value::Int(n),
+                            None => return Err(VmError::IntegerOverflow { ip: self.op_ip, opcode }),
+                        },
+                        Value::Float(n) => Value::Float(-n),
+                        value => return Err(self.invalid_operand(&value)),
+                    };
+                    self.push_stack(value)?;
                 },
-                OpCode::MUL => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    self.push_stack(a * b);
+                OpCode::BAND => self.bitwise(|a, b| Some(a & b))?,
+                OpCode::BOR => self.bitwise(|a, b| Some(a | b))?,
+                OpCode::BXOR => self.bitwise(|a, b| Some(a ^ b))?,
+                OpCode::SHL => self.bitwise(shift_left)?,
+                OpCode::SHR => self.bitwise(shift_right)?,
+                OpCode::BNOT => {
+                    let value = match self.pop_stack()? {
+                        Value::Int(n) => Value::Int(!n),
+                        value => return Err(self.invalid_operand(&value)),
+                    };
+                    self.push_stack(value)?;
                 },
-                OpCode::DIV => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    self.push_stack(a / b);
+                OpCode::NOT => {
+                    let val = self.pop_stack()?;
+                    self.push_stack(Value::Bool(!val.is_truthy()))?;
                 },
                 OpCode::PRINT => {
-                    let val = self.pop_stack();
-                    if let Err(_) = writeln!(stdout, "{}", val) {
+                    let val = self.pop_stack()?;
+                    if writeln!(stdout, "{}", val.display(&self.heap)).is_err() {
                         println!("ERROR: Could not write to output device!");
                     }
                 },
                 OpCode::GSTORE => {
-                    let addr = self.next_operand();
-                    let val = self.pop_stack();
-                    self.globals[addr as usize] = val;
+                    let addr = self.next_operand()?;
+                    let val = self.pop_stack()?;
+                    let slot = self.global_slot(addr)?;
+                    self.globals[slot] = val;
                 },
                 OpCode::GLOAD => {
-                    let addr = self.next_operand();
-                    self.push_stack(self.globals[addr as usize]);
+                    let addr = self.next_operand()?;
+                    let slot = self.global_slot(addr)?;
+                    self.push_stack(self.globals[slot])?;
                 },
                 OpCode::LLOAD => {
-                    let addr = self.next_operand();
-                    self.push_stack(self.stack[(self.fp as i32 + addr) as usize]);
+                    let addr = self.next_operand()?;
+                    let slot = self.local_slot(addr)?;
+                    self.push_stack(self.stack[slot])?;
                 },
                 OpCode::LSTORE => {
-                    let addr = self.next_operand();
-                    let val = self.pop_stack();
-                    self.stack[(self.fp as i32 + addr) as usize] = val;
+                    let addr = self.next_operand()?;
+                    let val = self.pop_stack()?;
+                    let slot = self.local_slot(addr)?;
+                    self.stack[slot] = val;
                 },
                 OpCode::CALL => {
-                    let fn_addr = self.next_operand() as usize;
-                    let fn_argc = self.next_operand();
+                    let fn_addr = self.next_operand()?;
+                    let fn_argc = self.next_operand()?;
                     let ret_addr = self.ip + 1;
+                    if fn_argc < 0 || fn_argc as usize > self.sp {
+                        return Err(VmError::StackUnderflow { ip: self.op_ip, opcode });
+                    }
                     // When CALL, push 3 values to the stack:
                     // - The current frame pointer
-                    self.push_stack(self.fp as i32);
+                    self.push_stack(Value::Int(self.fp as i64))?;
                     // - The return address
-                    self.push_stack(ret_addr as i32);
+                    self.push_stack(Value::Int(ret_addr as i64))?;
                     // - The number of args
-                    self.push_stack(fn_argc);
+                    self.push_stack(Value::Int(fn_argc as i64))?;
                     // make a jump
                     self.fp = self.sp;
-                    self.ip = fn_addr;
+                    self.ip = self.jump_target(fn_addr)?;
                     continue;
                 },
                 OpCode::RET => {
-                    let ret_val = self.pop_stack();
+                    let ret_val = self.pop_stack()?;
                     self.sp = self.fp;
-                    let fn_argc = self.pop_stack();
-                    let ret_addr = self.pop_stack() as usize;
-                    let prev_fp = self.pop_stack() as usize;
-                    self.fp = prev_fp;
-                    self.sp -= fn_argc as usize;
-                    self.push_stack(ret_val);
-                    self.ip = ret_addr;
+                    let fn_argc = self.pop_int()?;
+                    let ret_addr = self.pop_int()?;
+                    let prev_fp = self.pop_int()?;
+                    if prev_fp < 0 || prev_fp as usize > self.sp {
+                        return Err(VmError::InvalidLocal { ip: self.op_ip, opcode, addr: prev_fp });
+                    }
+                    self.fp = prev_fp as usize;
+                    self.sp = match usize::try_from(fn_argc).ok().and_then(|argc| self.sp.checked_sub(argc)) {
+                        Some(sp) => sp,
+                        None => return Err(VmError::StackUnderflow { ip: self.op_ip, opcode }),
+                    };
+                    self.push_stack(ret_val)?;
+                    self.ip = self.jump_target(ret_addr)?;
                     continue;
                 },
                 OpCode::POP => {
-                    self.pop_stack();
+                    self.pop_stack()?;
+                }
+                // Reserve the local slots of the call frame, they start as nil
+                OpCode::ENTER => {
+                    let size = self.next_operand()?;
+                    let size = match usize::try_from(size) {
+                        Ok(size) => size,
+                        Err(_) => return Err(VmError::InvalidFrameSize { ip: self.op_ip, opcode, size }),
+                    };
+                    if size > self.stack.len() - self.sp {
+                        return Err(VmError::StackOverflow { ip: self.op_ip, opcode });
+                    }
+                    self.stack[self.sp..self.sp + size].fill(Value::Nil);
+                    self.sp += size;
+                }
+                OpCode::DUP => {
+                    let val = self.peek(0)?;
+                    self.push_stack(val)?;
                 }
                 OpCode::EQ => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    let ret = if a == b { 1 } else { 0 };
-                    self.push_stack(ret);
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+                    self.push_stack(Value::Bool(a.equals(&b, &self.heap)))?;
                 },
                 OpCode::NE => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    let ret = if a != b { 1 } else { 0 };
-                    self.push_stack(ret);
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+                    self.push_stack(Value::Bool(!a.equals(&b, &self.heap)))?;
                 },
-                OpCode::GT => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    let ret = if a > b { 1 } else { 0 };
-                    self.push_stack(ret);
+                OpCode::GT => self.comparison(|ordering| ordering == Ordering::Greater)?,
+                OpCode::LT => self.comparison(|ordering| ordering == Ordering::Less)?,
+                OpCode::GE => self.comparison(|ordering| ordering != Ordering::Less)?,
+                OpCode::LE => self.comparison(|ordering| ordering != Ordering::Greater)?,
+                OpCode::GC => {
+                    self.collect_garbage();
+                    self.push_stack(Value::Nil)?;
                 },
-                OpCode::LT => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    let ret = if a < b { 1 } else { 0 };
-                    self.push_stack(ret);
+                // The string that PRINT would print
+                OpCode::STR => {
+                    let value = match self.pop_stack()? {
+                        value @ Value::String(_) => value,
+                        value => {
+                            let string = value.display(&self.heap).to_string();
+                            self.alloc_string(string)
+                        }
+                    };
+                    self.push_stack(value)?;
                 },
-                OpCode::GE => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    let ret = if a >= b { 1 } else { 0 };
-                    self.push_stack(ret);
+                OpCode::LEN => {
+                    let len = match self.pop_stack()? {
+                        Value::String(obj) => self.heap.string(obj).chars().count(),
+                        value => return Err(self.invalid_operand(&value)),
+                    };
+                    self.push_stack(Value::Int(len as i64))?;
                 },
-                OpCode::LE => {
-                    let a = self.pop_stack();
-                    let b = self.pop_stack();
-                    let ret = if a <= b { 1 } else { 0 };
-                    self.push_stack(ret);
+                OpCode::INDEX => {
+                    let index = self.pop_stack()?;
+                    let target = self.pop_stack()?;
+                    let (obj, index) = match (target, index) {
+                        (Value::String(obj), Value::Int(index)) => (obj, index),
+                        (target, index) => return Err(self.type_mismatch(&target, &index)),
+                    };
+                    let string = self.heap.string(obj);
+                    let c = match usize::try_from(index).ok().and_then(|index| string.chars().nth(index)) {
+                        Some(c) => c,
+                        None => return Err(VmError::IndexOutOfBounds {
+                            ip: self.op_ip,
+                            opcode,
+                            index,
+                            len: string.chars().count(),
+                        }),
+                    };
+                    let value = self.alloc_string(c.to_string());
+                    self.push_stack(value)?;
+                },
+                OpCode::SLICE => {
+                    let end = self.pop_stack()?;
+                    let start = self.pop_stack()?;
+                    let target = self.pop_stack()?;
+                    let (obj, start, end) = match (target, start, end) {
+                        (Value::String(obj), Value::Int(start), Value::Int(end)) => (obj, start, end),
+                        (Value::String(_), Value::Int(_), end) => return Err(self.type_mismatch(&target, &end)),
+                        (target, start, _) => return Err(self.type_mismatch(&target, &start)),
+                    };
+                    let string = self.heap.string(obj);
+                    let range = match (usize::try_from(start), usize::try_from(end)) {
+                        (Ok(start), Ok(end)) => char_range(string, start, end),
+                        _ => None,
+                    };
+                    let (from, to) = match range {
+                        Some(range) => range,
+                        None => return Err(VmError::InvalidSlice {
+                            ip: self.op_ip,
+                            opcode,
+                            start,
+                            end,
+                            len: string.chars().count(),
+                        }),
+                    };
+                    let value = self.alloc_string(string[from..to].to_string());
+                    self.push_stack(value)?;
                 },
                 OpCode::JMP => {
-                    self.ip = self.next_operand() as usize;
+                    let addr = self.next_operand()?;
+                    self.ip = self.jump_target(addr)?;
                     continue;
                 },
                 OpCode::JMP0 => {
-                    let addr = self.next_operand() as usize;
-                    let val = self.pop_stack();
-                    if val == 0 {
-                        self.ip = addr;
+                    let addr = self.next_operand()?;
+                    let val = self.pop_stack()?;
+                    if !val.is_truthy() {
+                        self.ip = self.jump_target(addr)?;
                         continue;
                     }
                 },
                 OpCode::JMP1 => {
-                    let addr = self.next_operand() as usize;
-                    let val = self.pop_stack();
-                    if val == 1 {
-                        self.ip = addr;
+                    let addr = self.next_operand()?;
+                    let val = self.pop_stack()?;
+                    if val.is_truthy() {
+                        self.ip = self.jump_target(addr)?;
                         continue;
                     }
                 },
             }
             self.ip += 1;
         }
+        Ok(())
     }
+
+    // Pop the right operand, then the left one, and push the result: ints
+    // stay ints, a float on either side makes the result a float
+    fn arithmetic(&mut self, int_op: fn(i64, i64) -> Option<i64>, float_op: fn(f64, f64) -> f64) -> VmResult<()> {
+        let b = self.pop_stack()?;
+        let a = self.pop_stack()?;
+        let result = match (&a, &b) {
+            (Value::Int(_), Value::Int(0)) if matches!(self.opcode, OpCode::DIV | OpCode::MOD) => {
+                return Err(VmError::DivisionByZero { ip: self.op_ip, opcode: self.opcode });
+            }
+            (Value::Int(a), Value::Int(b)) => match int_op(*a, *b) {
+                Some(n) => Value::Int(n),
+                None => return Err(VmError::IntegerOverflow { ip: self.op_ip, opcode: self.opcode }),
+            },
+            _ => match a.as_floats(&b) {
+                Some((a, b)) => Value::Float(float_op(a, b)),
+                None => return Err(self.type_mismatch(&a, &b)),
+            },
+        };
+        self.push_stack(result)
+    }
+
+    // An int to the power of a positive int is an int, a negative exponent
+    // gives a float like any float operand does
+    fn power(&mut self) -> VmResult<()> {
+        let b = self.pop_stack()?;
+        let a = self.pop_stack()?;
+        let result = match (a, b) {
+            (Value::Int(a), Value::Int(b)) if b >= 0 => {
+                match checked_pow(a, b) {
+                    Some(n) => Value::Int(n),
+                    None => return Err(VmError::IntegerOverflow { ip: self.op_ip, opcode: self.opcode }),
+                }
+            }
+            (Value::Int(a), Value::Int(b)) => Value::Float((a as f64).powf(b as f64)),
+            _ => match a.as_floats(&b) {
+                Some((a, b)) => Value::Float(a.powf(b)),
+                None => return Err(self.type_mismatch(&a, &b)),
+            },
+        };
+        self.push_stack(result)
+    }
+
+    // The bitwise operators only work on ints, `op` returns None for a
+    // negative shift count
+    fn bitwise(&mut self, op: fn(i64, i64) -> Option<i64>) -> VmResult<()> {
+        let b = self.pop_stack()?;
+        let a = self.pop_stack()?;
+        let result = match (a, b) {
+            (Value::Int(a), Value::Int(b)) => match op(a, b) {
+                Some(n) => n,
+                None => return Err(VmError::NegativeShift { ip: self.op_ip, opcode: self.opcode, count: b }),
+            },
+            _ => return Err(self.type_mismatch(&a, &b)),
+        };
+        self.push_stack(Value::Int(result))
+    }
+
+    fn comparison(&mut self, test: fn(Ordering) -> bool) -> VmResult<()> {
+        let b = self.pop_stack()?;
+        let a = self.pop_stack()?;
+        let result = match a.compare(&b, &self.heap) {
+            Some(ordering) => test(ordering),
+            // NaN is not less, greater or equal to anything
+            None if a.is_number() && b.is_number() => false,
+            None => return Err(self.type_mismatch(&a, &b)),
+        };
+        self.push_stack(Value::Bool(result))
+    }
+
+    // The value `depth` slots below the top of the stack, without popping it
+    fn peek(&self, depth: usize) -> VmResult<Value> {
+        match self.sp.checked_sub(depth + 1) {
+            Some(slot) => Ok(self.stack[slot]),
+            None => Err(VmError::StackUnderflow { ip: self.op_ip, opcode: self.opcode }),
+        }
+    }
+
+    fn constant(&mut self, index: i32) -> VmResult<Value> {
+        let slot = usize::try_from(index).ok().filter(|index| *index < self.constants.len());
+        let slot = match slot {
+            Some(slot) => slot,
+            None => return Err(VmError::InvalidConstant { ip: self.op_ip, opcode: self.opcode, index }),
+        };
+        if let Some(value) = self.constant_values[slot] {
+            return Ok(value);
+        }
+        let value = match &self.constants[slot] {
+            Constant::Int(n) => Value::Int(*n),
+            Constant::Float(n) => Value::Float(*n),
+            Constant::String(s) => self.alloc_string(s.to_string()),
+            Constant::Function { .. } => {
+                return Err(VmError::InvalidConstant { ip: self.op_ip, opcode: self.opcode, index });
+            }
+        };
+        self.constant_values[slot] = Some(value);
+        Ok(value)
+    }
+
+    fn invalid_operand(&self, value: &Value) -> VmError {
+        VmError::InvalidOperand {
+            ip: self.op_ip,
+            opcode: self.opcode,
+            found: value.type_name(),
+        }
+    }
+
+    fn type_mismatch(&self, left: &Value, right: &Value) -> VmError {
+        VmError::TypeMismatch {
+            ip: self.op_ip,
+            opcode: self.opcode,
+            left: left.type_name(),
+            right: right.type_name(),
+        }
+    }
+
+    // The values saved in a call frame are ints
+    fn pop_int(&mut self) -> VmResult<i32> {
+        match self.pop_stack()? {
+            Value::Int(n) => Ok(n as i32),
+            value => Err(self.invalid_operand(&value)),
+        }
+    }
+
+    fn global_slot(&self, addr: i32) -> VmResult<usize> {
+        if self.verified {
+            return Ok(addr as usize);
+        }
+        match usize::try_from(addr) {
+            Ok(slot) if slot < self.globals.len() => Ok(slot),
+            _ => Err(VmError::InvalidGlobal { ip: self.op_ip, opcode: self.opcode, addr }),
+        }
+    }
+
+    // Locals can only be read and written below the top of the stack
+    fn local_slot(&self, addr: i32) -> VmResult<usize> {
+        if self.verified {
+            return Ok((self.fp as i32 + addr) as usize);
+        }
+        match usize::try_from(self.fp as i64 + addr as i64) {
+            Ok(slot) if slot < self.sp => Ok(slot),
+            _ => Err(VmError::InvalidLocal { ip: self.op_ip, opcode: self.opcode, addr }),
+        }
+    }
+
+    fn jump_target(&self, addr: i32) -> VmResult<usize> {
+        if self.verified {
+            return Ok(addr as usize);
+        }
+        match usize::try_from(addr) {
+            Ok(target) if target < self.program.len() => Ok(target),
+            _ => Err(VmError::InvalidJump { ip: self.op_ip, opcode: self.opcode, addr }),
+        }
+    }
+}
+
+// The bits shifted out are lost, so shifting by 64 or more bits gives 0
+fn shift_left(n: i64, count: i64) -> Option<i64> {
+    match count {
+        0..=63 => Some(n << count),
+        64.. => Some(0),
+        _ => None,
+    }
+}
+
+// Exponentiation by squaring, so an exponent past u32::MAX still works
+// when the result fits, like `(-1) ** 5000000000`
+fn checked_pow(mut base: i64, mut exp: i64) -> Option<i64> {
+    let mut result: i64 = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result.checked_mul(base)?;
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base = base.checked_mul(base)?;
+        }
+    }
+    Some(result)
+}
+
+// An arithmetic shift: the sign bit is copied, so shifting by 64 or more
+// bits gives 0, or -1 for a negative number
+fn shift_right(n: i64, count: i64) -> Option<i64> {
+    if count < 0 {
+        return None;
+    }
+    Some(n >> count.min(63))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::bytecode::{OpCode, FUNC_PARAM_OFFSET};
-    use super::VirtualMachine;
+    use crate::bytecode::{verifier::VerifyError, Constant, OpCode, Program, FUNC_PARAM_OFFSET};
+    use super::{heap::GcConfig, VirtualMachine, VmError};
 
     #[test]
     fn test_simple_program() {
@@ -223,7 +806,7 @@ mod tests {
         ];
         let mut vm = VirtualMachine::new();
         vm.load_program(program, 0);
-        vm.run(&mut stdout);
+        vm.run(&mut stdout).unwrap();
 
         let stdout_str = std::str::from_utf8(&stdout).unwrap();
         assert_eq!(stdout_str, "15\nBYE!\n");
@@ -251,10 +834,10 @@ mod tests {
         ];
         let mut vm = VirtualMachine::new();
         vm.load_program(program, 0);
-        vm.run(&mut stdout);
+        vm.run(&mut stdout).unwrap();
 
         let stdout_str = std::str::from_utf8(&stdout).unwrap();
-        assert_eq!(stdout_str, "0\nBYE!\n");
+        assert_eq!(stdout_str, "1\nBYE!\n");
     }
 
     #[test]
@@ -284,9 +867,562 @@ mod tests {
         ];
         let mut vm = VirtualMachine::new();
         vm.load_program(program, 9);
-        vm.run(&mut stdout);
+        vm.run(&mut stdout).unwrap();
 
         let stdout_str = std::str::from_utf8(&stdout).unwrap();
         assert_eq!(stdout_str, "54\nBYE!\n");
     }
+
+    #[test]
+    fn test_append_program() {
+        let mut stdout = vec![];
+        let mut vm = VirtualMachine::new();
+        vm.set_quiet(true);
+        // let $v0 = 7
+        vm.load_program(vec![
+            OpCode::PUSH as i32, 7,             // 000
+            OpCode::GSTORE as i32, 0,           // 002
+            OpCode::HALT as i32,                // 004
+        ], 0);
+        vm.run(&mut stdout).unwrap();
+        // print($v0 * 3)
+        vm.append_program(vec![
+            OpCode::GLOAD as i32, 0,            // 005
+            OpCode::PUSH as i32, 3,             // 007
+            OpCode::MUL as i32,                 // 009
+            OpCode::PRINT as i32,               // 010
+            OpCode::HALT as i32,                // 011
+        ], 5);
+        assert_eq!(vm.program_len(), 12);
+        vm.run(&mut stdout).unwrap();
+
+        let stdout_str = std::str::from_utf8(&stdout).unwrap();
+        assert_eq!(stdout_str, "21\n");
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let run = |program: Vec<i32>| {
+            let mut vm = VirtualMachine::new();
+            vm.load_program(program, 0);
+            vm.run(&mut vec![]).unwrap_err()
+        };
+        assert_eq!(
+            run(vec![OpCode::PUSH as i32, 1, OpCode::ADD as i32, OpCode::HALT as i32]),
+            VmError::StackUnderflow { ip: 2, opcode: OpCode::ADD },
+        );
+        assert_eq!(
+            run(vec![
+                OpCode::PUSH as i32, 1,
+                OpCode::PUSH as i32, 0,
+                OpCode::DIV as i32,
+                OpCode::HALT as i32,
+            ]),
+            VmError::DivisionByZero { ip: 4, opcode: OpCode::DIV },
+        );
+        assert_eq!(
+            run(vec![OpCode::PUSH as i32, 1, OpCode::GSTORE as i32, 4096, OpCode::HALT as i32]),
+            VmError::InvalidGlobal { ip: 2, opcode: OpCode::GSTORE, addr: 4096 },
+        );
+        assert_eq!(
+            run(vec![OpCode::JMP as i32, 99]),
+            VmError::InvalidJump { ip: 0, opcode: OpCode::JMP, addr: 99 },
+        );
+        assert_eq!(
+            run(vec![OpCode::PUSH as i32]),
+            VmError::MissingOperand { ip: 0, opcode: OpCode::PUSH },
+        );
+        assert_eq!(
+            run(vec![
+                OpCode::PUSH as i32, i32::MAX,
+                OpCode::PUSH as i32, i32::MAX,
+                OpCode::MUL as i32,
+                OpCode::PUSH as i32, 4,
+                OpCode::MUL as i32,
+            ]),
+            VmError::IntegerOverflow { ip: 7, opcode: OpCode::MUL },
+        );
+        assert_eq!(
+            run(vec![OpCode::TRUE as i32, OpCode::PUSH as i32, 1, OpCode::ADD as i32]),
+            VmError::TypeMismatch { ip: 3, opcode: OpCode::ADD, left: "bool", right: "int" },
+        );
+        assert_eq!(
+            run(vec![OpCode::PUSH as i32, 1, OpCode::NIL as i32, OpCode::LT as i32]),
+            VmError::TypeMismatch { ip: 3, opcode: OpCode::LT, left: "int", right: "nil" },
+        );
+        assert_eq!(
+            run(vec![OpCode::PUSH as i32, 1, OpCode::PUSH as i32, 0, OpCode::MOD as i32, OpCode::HALT as i32]),
+            VmError::DivisionByZero { ip: 4, opcode: OpCode::MOD },
+        );
+        assert_eq!(
+            run(vec![OpCode::PUSH as i32, 2, OpCode::PUSH as i32, 64, OpCode::POW as i32, OpCode::HALT as i32]),
+            VmError::IntegerOverflow { ip: 4, opcode: OpCode::POW },
+        );
+        assert_eq!(
+            run(vec![OpCode::TRUE as i32, OpCode::NEG as i32, OpCode::HALT as i32]),
+            VmError::InvalidOperand { ip: 1, opcode: OpCode::NEG, found: "bool" },
+        );
+        assert_eq!(
+            run(vec![OpCode::PUSH as i32, 1, OpCode::PUSH as i32, -1, OpCode::SHL as i32, OpCode::HALT as i32]),
+            VmError::NegativeShift { ip: 4, opcode: OpCode::SHL, count: -1 },
+        );
+        assert_eq!(
+            run(vec![OpCode::NIL as i32, OpCode::PUSH as i32, 1, OpCode::BAND as i32, OpCode::HALT as i32]),
+            VmError::TypeMismatch { ip: 3, opcode: OpCode::BAND, left: "nil", right: "int" },
+        );
+        assert_eq!(
+            run(vec![OpCode::TRUE as i32, OpCode::BNOT as i32, OpCode::HALT as i32]),
+            VmError::InvalidOperand { ip: 1, opcode: OpCode::BNOT, found: "bool" },
+        );
+        assert_eq!(run(vec![OpCode::PUSH as i32, 1]), VmError::EndOfProgram { ip: 2 });
+        assert_eq!(run(vec![OpCode::PUSH as i32, 1, -7]), VmError::InvalidOpcode { ip: 2, value: -7 });
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        // The right-hand sides divide by zero, so running them would fail
+        let run = |program: Vec<i32>| {
+            let mut stdout = vec![];
+            let mut vm = VirtualMachine::new();
+            vm.set_quiet(true);
+            vm.load_program(program, 0);
+            vm.run(&mut stdout).map(|_| String::from_utf8(stdout).unwrap())
+        };
+        // print(false && 1 / 0)
+        assert_eq!(run(vec![
+            OpCode::FALSE as i32,               // 000
+            OpCode::DUP as i32,                 // 001
+            OpCode::JMP0 as i32, 10,            // 002
+            OpCode::POP as i32,                 // 004
+            OpCode::PUSH as i32, 1,             // 005
+            OpCode::PUSH as i32, 0,             // 007
+            OpCode::DIV as i32,                 // 009
+            OpCode::PRINT as i32,               // 010
+            OpCode::HALT as i32,                // 011
+        ]), Ok("false\n".to_string()));
+        // print(7 || 1 / 0)
+        assert_eq!(run(vec![
+            OpCode::PUSH as i32, 7,             // 000
+            OpCode::DUP as i32,                 // 002
+            OpCode::JMP1 as i32, 11,            // 003
+            OpCode::POP as i32,                 // 005
+            OpCode::PUSH as i32, 1,             // 006
+            OpCode::PUSH as i32, 0,             // 008
+            OpCode::DIV as i32,                 // 010
+            OpCode::PRINT as i32,               // 011
+            OpCode::HALT as i32,                // 012
+        ]), Ok("7\n".to_string()));
+        // print(nil || 1 / 0) runs the right-hand side
+        assert_eq!(run(vec![
+            OpCode::NIL as i32,                 // 000
+            OpCode::DUP as i32,                 // 001
+            OpCode::JMP1 as i32, 10,            // 002
+            OpCode::POP as i32,                 // 004
+            OpCode::PUSH as i32, 1,             // 005
+            OpCode::PUSH as i32, 0,             // 007
+            OpCode::DIV as i32,                 // 009
+            OpCode::PRINT as i32,               // 010
+            OpCode::HALT as i32,                // 011
+        ]), Err(VmError::DivisionByZero { ip: 9, opcode: OpCode::DIV }));
+        assert_eq!(
+            run(vec![OpCode::DUP as i32, OpCode::HALT as i32]),
+            Err(VmError::StackUnderflow { ip: 0, opcode: OpCode::DUP }),
+        );
+    }
+
+    #[test]
+    fn test_truthiness() {
+        // JMP0 and JMP1 agree on every value: exactly one of them jumps
+        let values = [
+            (vec![OpCode::NIL as i32], false),
+            (vec![OpCode::FALSE as i32], false),
+            (vec![OpCode::TRUE as i32], true),
+            (vec![OpCode::PUSH as i32, 0], false),
+            (vec![OpCode::PUSH as i32, 1], true),
+            (vec![OpCode::PUSH as i32, 2], true),
+            (vec![OpCode::PUSH as i32, -1], true),
+        ];
+        for (value, truthy) in values.iter() {
+            for jump in [OpCode::JMP0, OpCode::JMP1].iter() {
+                // [value] JMPx taken; PUSH 0 PRINT HALT; taken: PUSH 1 PRINT HALT
+                let taken = value.len() + 6;
+                let mut program = value.clone();
+                program.extend(vec![
+                    *jump as i32, taken as i32,
+                    OpCode::PUSH as i32, 0, OpCode::PRINT as i32, OpCode::HALT as i32,
+                    OpCode::PUSH as i32, 1, OpCode::PRINT as i32, OpCode::HALT as i32,
+                ]);
+                let mut stdout = vec![];
+                let mut vm = VirtualMachine::new();
+                vm.set_quiet(true);
+                vm.load_program(program, 0);
+                vm.run(&mut stdout).unwrap();
+                let jumped = stdout == b"1\n";
+                assert_eq!(jumped, (*jump == OpCode::JMP1) == *truthy, "{:?} {:?}", jump, value);
+            }
+            let mut program = value.clone();
+            program.extend(vec![OpCode::NOT as i32, OpCode::PRINT as i32, OpCode::HALT as i32]);
+            let mut stdout = vec![];
+            let mut vm = VirtualMachine::new();
+            vm.set_quiet(true);
+            vm.load_program(program, 0);
+            vm.run(&mut stdout).unwrap();
+            assert_eq!(stdout, format!("{}\n", !truthy).as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_frame_locals() {
+        let mut stdout = vec![];
+        let program = vec![
+            // fn f(a) { let x; let y; print(y); x = a * 2; y = x + 1; return y }
+            OpCode::ENTER as i32, 2,                                  // 000
+            OpCode::LLOAD as i32, 1,                                  // 002
+            OpCode::PRINT as i32,                                     // 004
+            OpCode::LLOAD as i32, -(FUNC_PARAM_OFFSET + 1),           // 005
+            OpCode::PUSH as i32, 2,                                   // 007
+            OpCode::MUL as i32,                                       // 009
+            OpCode::LSTORE as i32, 0,                                 // 010
+            // The values pushed now stay above the locals
+            OpCode::LLOAD as i32, 0,                                  // 012
+            OpCode::PUSH as i32, 1,                                   // 014
+            OpCode::ADD as i32,                                       // 016
+            OpCode::LSTORE as i32, 1,                                 // 017
+            OpCode::LLOAD as i32, 1,                                  // 019
+            OpCode::RET as i32,                                       // 021
+            // print(f(5)) print(f(10))
+            OpCode::PUSH as i32, 5,                                   // 022
+            OpCode::CALL as i32, 0, 1,                                // 024
+            OpCode::PRINT as i32,                                     // 027
+            OpCode::PUSH as i32, 10,                                  // 028
+            OpCode::CALL as i32, 0, 1,                                // 030
+            OpCode::PRINT as i32,                                     // 033
+            OpCode::HALT as i32,                                      // 034
+        ];
+        let mut vm = VirtualMachine::new();
+        vm.set_quiet(true);
+        vm.load_program(program, 22);
+        vm.run(&mut stdout).unwrap();
+        // The second call starts with nil locals again, not the values of
+        // the first call left on the stack
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "nil\n11\nnil\n21\n");
+
+        let mut vm = VirtualMachine::new();
+        vm.load_program(vec![OpCode::ENTER as i32, -1, OpCode::HALT as i32], 0);
+        assert_eq!(
+            vm.run(&mut stdout),
+            Err(VmError::InvalidFrameSize { ip: 0, opcode: OpCode::ENTER, size: -1 }),
+        );
+        let mut vm = VirtualMachine::new();
+        vm.load_program(vec![OpCode::ENTER as i32, 1 << 20, OpCode::HALT as i32], 0);
+        assert_eq!(vm.run(&mut stdout), Err(VmError::StackOverflow { ip: 0, opcode: OpCode::ENTER }));
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // fn f() -> f()
+        let program = vec![
+            OpCode::CALL as i32, 0, 0,          // 000
+            OpCode::RET as i32,                 // 003
+        ];
+        let mut vm = VirtualMachine::new();
+        vm.load_program(program, 0);
+        let error = vm.run(&mut vec![]).unwrap_err();
+        assert_eq!(error, VmError::StackOverflow { ip: 0, opcode: OpCode::CALL });
+        // The machine can still run after an error
+        vm.load_program(vec![OpCode::PUSH as i32, 1, OpCode::PRINT as i32, OpCode::HALT as i32], 0);
+        let mut stdout = vec![];
+        vm.run(&mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "1\nBYE!\n");
+    }
+
+    #[test]
+    fn test_load_verified() {
+        let mut vm = VirtualMachine::new();
+        let bad = Program {
+            code: vec![OpCode::PUSH as i32, 1, OpCode::JMP as i32, 1],
+            entrypoint: 0,
+            constants: vec![],
+            spans: vec![],
+        };
+        assert_eq!(
+            vm.load_verified(&bad),
+            Err(VerifyError::InvalidJump { addr: 2, opcode: OpCode::JMP, target: 1 }),
+        );
+        let good = Program {
+            code: vec![
+                OpCode::LLOAD as i32, -(FUNC_PARAM_OFFSET + 1),   // 000
+                OpCode::PUSH as i32, 3,                           // 002
+                OpCode::MUL as i32,                               // 004
+                OpCode::RET as i32,                               // 005
+                OpCode::PUSH as i32, 14,                          // 006
+                OpCode::CALL as i32, 0, 1,                        // 008
+                OpCode::PRINT as i32,                             // 011
+                OpCode::HALT as i32,                              // 012
+            ],
+            entrypoint: 6,
+            constants: vec![],
+            spans: vec![],
+        };
+        vm.load_verified(&good).unwrap();
+        let mut stdout = vec![];
+        vm.run(&mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "42\nBYE!\n");
+
+        // The values left on the stack by the previous program are not the
+        // locals of the next one
+        let mut vm = VirtualMachine::new();
+        vm.set_quiet(true);
+        vm.load_program(vec![OpCode::PUSH as i32, 7, OpCode::PUSH as i32, 7, OpCode::HALT as i32], 0);
+        vm.run(&mut vec![]).unwrap();
+        let locals = Program {
+            code: vec![
+                OpCode::ENTER as i32, 1,                          // 000
+                OpCode::LLOAD as i32, 0,                          // 002
+                OpCode::PRINT as i32,                             // 004
+                OpCode::POP as i32,                               // 005
+                OpCode::HALT as i32,                              // 006
+            ],
+            entrypoint: 0,
+            constants: vec![],
+            spans: vec![],
+        };
+        vm.load_verified(&locals).unwrap();
+        let mut stdout = vec![];
+        vm.run(&mut stdout).unwrap();
+        assert_eq!(std::str::from_utf8(&stdout).unwrap(), "nil\n");
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let mut stdout = vec![];
+        let program = vec![
+            // print(7 / 2)
+            OpCode::PUSH as i32, 7,             // 000
+            OpCode::PUSH as i32, 2,             // 002
+            OpCode::DIV as i32,                 // 004
+            OpCode::PRINT as i32,               // 005
+            // print(1 == 1)
+            OpCode::PUSH as i32, 1,             // 006
+            OpCode::PUSH as i32, 1,             // 008
+            OpCode::EQ as i32,                  // 010
+            OpCode::PRINT as i32,               // 011
+            // print(nil != false)
+            OpCode::NIL as i32,                 // 012
+            OpCode::FALSE as i32,               // 013
+            OpCode::NE as i32,                  // 014
+            OpCode::PRINT as i32,               // 015
+            OpCode::HALT as i32,                // 016
+        ];
+        let mut vm = VirtualMachine::new();
+        vm.load_program(program, 0);
+        vm.run(&mut stdout).unwrap();
+
+        let stdout_str = std::str::from_utf8(&stdout).unwrap();
+        assert_eq!(stdout_str, "3\ntrue\ntrue\nBYE!\n");
+    }
+
+    #[test]
+    fn test_power() {
+        let run = |base: i32| {
+            let program = Program {
+                code: vec![
+                    OpCode::PUSH as i32, base,          // 000
+                    OpCode::CONST as i32, 0,            // 002
+                    OpCode::POW as i32,                 // 004
+                    OpCode::PRINT as i32,               // 005
+                    OpCode::HALT as i32,                // 006
+                ],
+                entrypoint: 0,
+                constants: vec![Constant::Int(5_000_000_001)],
+                spans: vec![],
+            };
+            let mut stdout = vec![];
+            let mut vm = VirtualMachine::new();
+            vm.set_quiet(true);
+            vm.load_verified(&program).unwrap();
+            vm.run(&mut stdout).map(|_| String::from_utf8(stdout).unwrap())
+        };
+        // An exponent past u32::MAX is fine when the result fits
+        assert_eq!(run(0), Ok("0\n".to_string()));
+        assert_eq!(run(1), Ok("1\n".to_string()));
+        assert_eq!(run(-1), Ok("-1\n".to_string()));
+        assert_eq!(run(2), Err(VmError::IntegerOverflow { ip: 4, opcode: OpCode::POW }));
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut stdout = vec![];
+        let program = Program {
+            code: vec![
+                OpCode::CONST as i32, 0,            // 000
+                OpCode::PRINT as i32,               // 002
+                OpCode::CONST as i32, 1,            // 003
+                OpCode::PRINT as i32,               // 005
+                OpCode::CONST as i32, 2,            // 006
+                OpCode::PUSH as i32, 1,             // 008
+                OpCode::ADD as i32,                 // 010
+                OpCode::PRINT as i32,               // 011
+                OpCode::HALT as i32,                // 012
+            ],
+            entrypoint: 0,
+            constants: vec![
+                Constant::String("xin chào".into()),
+                Constant::Float(0.5),
+                Constant::Int(1 << 40),
+            ],
+            spans: vec![],
+        };
+        let mut vm = VirtualMachine::new();
+        vm.load_verified(&program).unwrap();
+        vm.run(&mut stdout).unwrap();
+
+        let stdout_str = std::str::from_utf8(&stdout).unwrap();
+        assert_eq!(stdout_str, "xin chào\n0.5\n1099511627777\nBYE!\n");
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut stdout = vec![];
+        let program = Program {
+            code: vec![
+                // print("xin " + "chào")
+                OpCode::CONST as i32, 0,            // 000
+                OpCode::CONST as i32, 1,            // 002
+                OpCode::ADD as i32,                 // 004
+                OpCode::PRINT as i32,               // 005
+                // print(len("tên"))
+                OpCode::CONST as i32, 2,            // 006
+                OpCode::LEN as i32,                 // 008
+                OpCode::PRINT as i32,               // 009
+                // print("tên"[1])
+                OpCode::CONST as i32, 2,            // 010
+                OpCode::PUSH as i32, 1,             // 012
+                OpCode::INDEX as i32,               // 014
+                OpCode::PRINT as i32,               // 015
+                // print("tên"[1..3])
+                OpCode::CONST as i32, 2,            // 016
+                OpCode::PUSH as i32, 1,             // 018
+                OpCode::PUSH as i32, 3,             // 020
+                OpCode::SLICE as i32,               // 022
+                OpCode::PRINT as i32,               // 023
+                // print("chào" < "xin ")
+                OpCode::CONST as i32, 1,            // 024
+                OpCode::CONST as i32, 0,            // 026
+                OpCode::LT as i32,                  // 028
+                OpCode::PRINT as i32,               // 029
+                // print("tên"[0..1] + "ên" == "tên")
+                OpCode::CONST as i32, 2,            // 030
+                OpCode::PUSH as i32, 0,             // 032
+                OpCode::PUSH as i32, 1,             // 034
+                OpCode::SLICE as i32,               // 036
+                OpCode::CONST as i32, 3,            // 037
+                OpCode::ADD as i32,                 // 039
+                OpCode::CONST as i32, 2,            // 040
+                OpCode::EQ as i32,                  // 042
+                OpCode::PRINT as i32,               // 043
+                OpCode::HALT as i32,                // 044
+            ],
+            entrypoint: 0,
+            constants: vec![
+                Constant::String("xin ".into()),
+                Constant::String("chào".into()),
+                Constant::String("tên".into()),
+                Constant::String("ên".into()),
+            ],
+            spans: vec![],
+        };
+        let mut vm = VirtualMachine::new();
+        vm.load_verified(&program).unwrap();
+        vm.run(&mut stdout).unwrap();
+
+        let stdout_str = std::str::from_utf8(&stdout).unwrap();
+        assert_eq!(stdout_str, "xin chào\n3\nê\nên\ntrue\ntrue\nBYE!\n");
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        let program = Program {
+            code: vec![
+                // let name = "a" + "b"
+                OpCode::CONST as i32, 0,            // 000
+                OpCode::CONST as i32, 1,            // 002
+                OpCode::ADD as i32,                 // 004
+                OpCode::GSTORE as i32, 0,           // 005
+                // "a" + "b" + "b"
+                OpCode::GLOAD as i32, 0,            // 007
+                OpCode::CONST as i32, 1,            // 009
+                OpCode::ADD as i32,                 // 011
+                OpCode::POP as i32,                 // 012
+                // gc()
+                OpCode::GC as i32,                  // 013
+                OpCode::POP as i32,                 // 014
+                OpCode::HALT as i32,                // 015
+            ],
+            entrypoint: 0,
+            constants: vec![Constant::String("a".into()), Constant::String("b".into())],
+            spans: vec![],
+        };
+        let mut vm = VirtualMachine::new();
+        vm.set_quiet(true);
+        vm.load_verified(&program).unwrap();
+        vm.run(&mut vec![]).unwrap();
+
+        // Only "abb" is garbage, the constants and the global are roots
+        let stats = vm.gc_stats();
+        assert_eq!(stats.collections, 1);
+        assert_eq!(stats.objects_freed, 1);
+        assert!(stats.bytes_freed > 0);
+        assert_eq!(vm.heap_objects(), 3);
+
+        // With no threshold, every allocation collects first
+        let mut vm = VirtualMachine::new();
+        vm.set_quiet(true);
+        vm.set_gc_config(GcConfig { threshold: 0, growth_factor: 1 });
+        vm.load_verified(&program).unwrap();
+        vm.run(&mut vec![]).unwrap();
+        assert_eq!(vm.gc_stats().collections, 5);
+        assert_eq!(vm.heap_objects(), 3);
+    }
+
+    #[test]
+    fn test_string_errors() {
+        let run = |code: Vec<i32>| {
+            let program = Program {
+                code,
+                entrypoint: 0,
+                constants: vec![Constant::String("tên".into())],
+                spans: vec![],
+            };
+            let mut vm = VirtualMachine::new();
+            vm.load_verified(&program).unwrap();
+            vm.run(&mut vec![]).unwrap_err()
+        };
+        assert_eq!(
+            run(vec![OpCode::CONST as i32, 0, OpCode::PUSH as i32, 3, OpCode::INDEX as i32, OpCode::HALT as i32]),
+            VmError::IndexOutOfBounds { ip: 4, opcode: OpCode::INDEX, index: 3, len: 3 },
+        );
+        assert_eq!(
+            run(vec![OpCode::CONST as i32, 0, OpCode::PUSH as i32, -1, OpCode::INDEX as i32, OpCode::HALT as i32]),
+            VmError::IndexOutOfBounds { ip: 4, opcode: OpCode::INDEX, index: -1, len: 3 },
+        );
+        assert_eq!(
+            run(vec![
+                OpCode::CONST as i32, 0,
+                OpCode::PUSH as i32, 2,
+                OpCode::PUSH as i32, 1,
+                OpCode::SLICE as i32,
+                OpCode::HALT as i32,
+            ]),
+            VmError::InvalidSlice { ip: 6, opcode: OpCode::SLICE, start: 2, end: 1, len: 3 },
+        );
+        assert_eq!(
+            run(vec![OpCode::CONST as i32, 0, OpCode::PUSH as i32, 1, OpCode::ADD as i32, OpCode::HALT as i32]),
+            VmError::TypeMismatch { ip: 4, opcode: OpCode::ADD, left: "string", right: "int" },
+        );
+        assert_eq!(
+            run(vec![OpCode::PUSH as i32, 1, OpCode::LEN as i32, OpCode::HALT as i32]),
+            VmError::InvalidOperand { ip: 2, opcode: OpCode::LEN, found: "int" },
+        );
+    }
 }
@@ -0,0 +1,137 @@
+use std::cmp::Ordering;
+use super::heap::{Display, Heap, ObjRef};
+
+// The values that live on the stack and in the globals of the VirtualMachine.
+//
+// Ints and floats can be mixed in arithmetic and comparisons, the result
+// is a float as soon as one side is a float. Strings live on the Heap, and
+// are compared by their content. Every other combination of types is a
+// runtime error, except for `==` and `!=`, which are defined for everything:
+// values of different types are never equal.
+//
+// Every value can be used as a condition: `false`, `nil` and zero are
+// falsy, everything else, including the empty string, is truthy.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Value {
+    #[default]
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(ObjRef),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::String(_) => "string",
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Int(_) | Value::Float(_))
+    }
+
+    // The truthiness used by JMP0, JMP1 and NOT, and so by `if`, `!`,
+    // `&&` and `||`
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Nil | Value::Bool(false) | Value::Int(0) => false,
+            Value::Float(n) => *n != 0.0,
+            _ => true,
+        }
+    }
+
+    // Equality as seen by the `==` operator: an int is equal to a float
+    // with the same value, two strings are equal if they have the same chars
+    pub fn equals(&self, other: &Value, heap: &Heap) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Float(b)) | (Value::Float(b), Value::Int(a)) => *a as f64 == *b,
+            (Value::String(a), Value::String(b)) => heap.string(*a) == heap.string(*b),
+            (a, b) => a == b,
+        }
+    }
+
+    // The ordering used by `<`, `>`, `<=` and `>=`, None if the two values
+    // can't be compared, or one of them is NaN
+    pub fn compare(&self, other: &Value, heap: &Heap) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (Value::Int(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+            (Value::Float(a), Value::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::String(a), Value::String(b)) => Some(heap.string(*a).cmp(heap.string(*b))),
+            _ => None,
+        }
+    }
+
+    // Both values as floats, if they are numbers and at least one of them
+    // is a float
+    pub fn as_floats(&self, other: &Value) -> Option<(f64, f64)> {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => Some((*a, *b)),
+            (Value::Int(a), Value::Float(b)) => Some((*a as f64, *b)),
+            (Value::Float(a), Value::Int(b)) => Some((*a, *b as f64)),
+            _ => None,
+        }
+    }
+
+    pub fn display<'a>(&'a self, heap: &'a Heap) -> Display<'a> {
+        Display { value: self, heap }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use super::Value;
+    use crate::vm::heap::Heap;
+
+    #[test]
+    fn value_equality_test() {
+        let mut heap = Heap::new();
+        let a = heap.alloc_string("a".to_string());
+        let other_a = heap.alloc_string("a".to_string());
+        let b = heap.alloc_string("b".to_string());
+        assert!(Value::Int(2).equals(&Value::Float(2.0), &heap));
+        assert!(!Value::Int(0).equals(&Value::Bool(false), &heap));
+        assert!(!Value::Nil.equals(&Value::Int(0), &heap));
+        assert!(a.equals(&other_a, &heap));
+        assert!(!a.equals(&b, &heap));
+    }
+
+    #[test]
+    fn value_compare_test() {
+        let mut heap = Heap::new();
+        let apple = heap.alloc_string("apple".to_string());
+        let banana = heap.alloc_string("banana".to_string());
+        assert_eq!(Value::Int(1).compare(&Value::Float(1.5), &heap), Some(Ordering::Less));
+        assert_eq!(Value::Float(2.5).compare(&Value::Int(2), &heap), Some(Ordering::Greater));
+        assert_eq!(Value::Int(1).compare(&Value::Bool(true), &heap), None);
+        assert_eq!(Value::Float(f64::NAN).compare(&Value::Float(1.0), &heap), None);
+        assert_eq!(apple.compare(&banana, &heap), Some(Ordering::Less));
+        assert_eq!(apple.compare(&Value::Int(1), &heap), None);
+    }
+
+    #[test]
+    fn value_truthiness_test() {
+        let mut heap = Heap::new();
+        let empty = heap.alloc_string(String::new());
+        assert!(!Value::Nil.is_truthy());
+        assert!(!Value::Bool(false).is_truthy());
+        assert!(!Value::Int(0).is_truthy());
+        assert!(!Value::Float(0.0).is_truthy());
+        assert!(!Value::Float(-0.0).is_truthy());
+        assert!(Value::Bool(true).is_truthy());
+        assert!(Value::Int(2).is_truthy());
+        assert!(Value::Int(-1).is_truthy());
+        assert!(Value::Float(0.5).is_truthy());
+        assert!(Value::Float(f64::NAN).is_truthy());
+        assert!(empty.is_truthy());
+    }
+}
@@ -2,28 +2,130 @@
 mod bytecode;
 #[path = "./gust-compiler/mod.rs"]
 mod compiler;
+#[path = "./gust-diagnostics/mod.rs"]
+mod diagnostics;
+#[path = "./gust-repl/mod.rs"]
+mod repl;
 #[path = "./gust-vm/mod.rs"]
 mod vm;
 
-use compiler::lexer::Lexer;
+use std::{env, fs, io, process};
+use bytecode::{disassemble, Program};
+use compiler::{ast::Stmt, codegen, lexer::Lexer, parser::Parser, token::SpannedToken};
+use diagnostics::Diagnostic;
+use vm::{heap::GcConfig, VirtualMachine};
+
+const USAGE: &str = "Usage: gust <command> <file>
+       gust repl
+
+Commands:
+    run      Compile and execute a Gust program
+    tokens   Print the tokens produced by the lexer
+    ast      Print the syntax tree produced by the parser
+    disasm   Print the compiled bytecode
+    repl     Start an interactive session
+
+Environment:
+    GUST_GC_THRESHOLD   Heap size in bytes that triggers the first collection
+    GUST_GC_STATS       Print the garbage collector stats after `run`";
 
 fn main() {
-    let source = r#"
-    let x = 10
-    let y = x
-    let z = x + 5
-    if y >= x && z != 10 {
-        let hello = 100
-    }
-    let long_name = true"#;
-    let mut lexer = Lexer::new(source);
-    loop {
-        let token = lexer.next();
-        if token.is_some() {
-            println!("Token {:?}", token);
-        } else {
-            break;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, path) = match args.as_slice() {
+        [command] if command == "repl" => {
+            repl::start();
+            return;
+        }
+        [command, path] => (command.as_str(), path.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
         }
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("error: could not read {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+    match command {
+        "run" => {
+            let program = compile(path, &source);
+            let mut vm = VirtualMachine::new();
+            vm.set_gc_config(gc_config());
+            if let Err(err) = vm.load_verified(&program) {
+                let mut diagnostic = err.to_diagnostic();
+                if let Some(span) = program.span_at(err.addr()) {
+                    diagnostic = diagnostic.with_span(span);
+                }
+                fail(path, &source, vec![diagnostic]);
+            }
+            if let Err(err) = vm.run(&mut io::stdout()) {
+                let mut diagnostic = err.to_diagnostic();
+                if let Some(span) = program.span_at(err.ip()) {
+                    diagnostic = diagnostic.with_span(span);
+                }
+                fail(path, &source, vec![diagnostic]);
+            }
+            if env::var_os("GUST_GC_STATS").is_some() {
+                let stats = vm.gc_stats();
+                eprintln!(
+                    "gc: {} collections, {} objects ({} bytes) freed in {:?}, {} objects ({} bytes) live",
+                    stats.collections,
+                    stats.objects_freed,
+                    stats.bytes_freed,
+                    stats.total_pause,
+                    vm.heap_objects(),
+                    vm.heap_size(),
+                );
+            }
+        }
+        "tokens" => match Lexer::new(&source).tokenize() {
+            Ok(tokens) => {
+                for SpannedToken { token, span } in tokens {
+                    println!("{}:{}\t{:?}", span.line, span.column, token);
+                }
+            }
+            Err(errors) => fail(path, &source, errors.iter().map(|err| err.to_diagnostic())),
+        },
+        "ast" => {
+            for stmt in parse(path, &source) {
+                println!("{:#?}", stmt);
+            }
+        }
+        "disasm" => print!("{}", disassemble(&compile(path, &source))),
+        _ => {
+            eprintln!("Unknown command `{}`\n\n{}", command, USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+fn parse<'a>(path: &str, source: &'a str) -> Vec<Stmt<'a>> {
+    match Parser::new(Lexer::new(source)).parse() {
+        Ok(program) => program,
+        Err(errors) => fail(path, source, errors.iter().map(|err| err.to_diagnostic())),
+    }
+}
+
+fn compile(path: &str, source: &str) -> Program {
+    match codegen::compile(&parse(path, source)) {
+        Ok(program) => program,
+        Err(err) => fail(path, source, vec![err.to_diagnostic()]),
     }
-    println!("END");
+}
+
+fn gc_config() -> GcConfig {
+    let mut config = GcConfig::default();
+    if let Some(threshold) = env::var("GUST_GC_THRESHOLD").ok().and_then(|value| value.parse().ok()) {
+        config.threshold = threshold;
+    }
+    config
+}
+
+fn fail(path: &str, source: &str, diagnostics: impl IntoIterator<Item = Diagnostic>) -> ! {
+    let diagnostics = diagnostics.into_iter().collect::<Vec<Diagnostic>>();
+    diagnostics::emit(&diagnostics, path, source);
+    process::exit(1);
 }